@@ -0,0 +1,19 @@
+use std::env;
+use std::process::exit;
+
+fn main() -> anyhow::Result<()> {
+    if env::var("CARGO").is_err() {
+        eprintln!("This binary may only be called via `cargo ndk-runner`, or as a cargo runner.");
+        exit(1);
+    }
+
+    // Cargo passes the subcommand name first when invoked as `cargo ndk-runner`, but not when
+    // invoking us as the runner for a target.
+    let skip = match env::args_os().nth(1) {
+        Some(arg) if arg == "ndk-runner" => 2,
+        _ => 1,
+    };
+    let args = env::args_os().skip(skip).collect::<Vec<_>>();
+
+    cargo_ndk::runner::run(args)
+}
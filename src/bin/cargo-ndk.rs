@@ -1,4 +1,6 @@
 use std::env;
+use std::ffi::OsString;
+use std::path::Path;
 use std::process::exit;
 
 /// We are avoiding using the Clang wrapper scripts in the NDK because they have
@@ -20,20 +22,65 @@ fn clang_linker_wrapper() -> ! {
     let target = std::env::var("_CARGO_NDK_LINK_TARGET")
         .expect("cargo-ndk rustc linker: didn't find _CARGO_NDK_LINK_TARGET env var");
 
-    let mut child = std::process::Command::new(&clang)
+    let args = args.collect::<Vec<_>>();
+
+    // Keep clang's intermediate files next to the output, and have it print the commands it
+    // runs, for debugging link failures that are otherwise hidden behind this wrapper.
+    let keep_temps = std::env::var_os("CARGO_NDK_KEEP_LINK_TEMPS").is_some();
+    if keep_temps {
+        let output = args
+            .iter()
+            .position(|arg| arg == "-o")
+            .and_then(|i| args.get(i + 1))
+            .map(Path::new)
+            .and_then(Path::parent);
+        if let Some(dir) = output {
+            eprintln!(
+                "cargo-ndk (as linker): keeping temporary files in {}",
+                dir.display()
+            );
+        }
+    }
+
+    let mut command = std::process::Command::new(&clang);
+    command
         .arg(target)
-        .args(args)
-        .spawn()
-        .unwrap_or_else(|err| {
-            eprintln!("cargo-ndk: Failed to spawn {clang:?} as linker: {err}");
-            std::process::exit(1)
-        });
+        .args(
+            keep_temps
+                .then_some(["-save-temps=obj", "-v"])
+                .into_iter()
+                .flatten(),
+        )
+        .args(args);
+
+    // Libraries from `--link-lib`, after the objects that need them
+    if let Ok(libs) = std::env::var("_CARGO_NDK_LINK_LIBS") {
+        command.args(libs.split_whitespace().map(|name| format!("-l{name}")));
+    }
+
+    if std::env::var_os("_CARGO_NDK_VERBOSE").is_some() {
+        eprintln!("cargo-ndk (as linker): running {command:?}");
+    }
+
+    let mut child = command.spawn().unwrap_or_else(|err| {
+        eprintln!("cargo-ndk: Failed to spawn {clang:?} as linker: {err}");
+        std::process::exit(1)
+    });
     let status = child.wait().unwrap_or_else(|err| {
         eprintln!("cargo-ndk (as linker): Failed to wait for {clang:?} to complete: {err}");
         std::process::exit(1);
     });
 
-    std::process::exit(status.code().unwrap_or(1))
+    std::process::exit(cargo_ndk::process::exit_code(&status))
+}
+
+/// Whether cargo-ndk was run as `cargo ndk`, rather than by rustc as the linker.
+///
+/// The wrapper env vars are inherited by build scripts too, so a nested `cargo ndk` invoked
+/// from one of them must not be mistaken for a link step. Cargo always passes the subcommand
+/// name as the first argument, whereas rustc only ever passes linker arguments.
+fn is_subcommand(mut args: impl Iterator<Item = OsString>) -> bool {
+    args.nth(1).is_some_and(|arg| arg == "ndk")
 }
 
 fn main() -> anyhow::Result<()> {
@@ -42,7 +89,7 @@ fn main() -> anyhow::Result<()> {
         exit(1);
     }
 
-    if std::env::var("_CARGO_NDK_LINK_TARGET").is_ok() {
+    if std::env::var("_CARGO_NDK_LINK_TARGET").is_ok() && !is_subcommand(std::env::args_os()) {
         clang_linker_wrapper();
     }
 
@@ -50,3 +97,42 @@ fn main() -> anyhow::Result<()> {
 
     cargo_ndk::cli::run(args)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> impl Iterator<Item = OsString> {
+        args.iter()
+            .map(OsString::from)
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn nested_cargo_ndk_is_a_subcommand() {
+        assert!(is_subcommand(args(&[
+            "cargo-ndk",
+            "ndk",
+            "-t",
+            "arm64-v8a",
+            "build"
+        ])));
+    }
+
+    #[test]
+    fn link_step_is_not_a_subcommand() {
+        assert!(!is_subcommand(args(&[
+            "cargo-ndk",
+            "-fuse-ld=lld",
+            "/tmp/rustc/symbols.o",
+            "-o",
+            "ndk",
+        ])));
+        assert!(!is_subcommand(args(&[
+            "cargo-ndk",
+            "@/tmp/rustc/linker-arguments"
+        ])));
+        assert!(!is_subcommand(args(&["cargo-ndk"])));
+    }
+}
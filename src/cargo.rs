@@ -2,15 +2,25 @@ use std::{
     collections::BTreeMap,
     env,
     ffi::OsString,
-    io::BufReader,
+    io::{BufRead, BufReader},
     path::{Path, PathBuf},
     process::{Command, Stdio},
+    str::FromStr,
+    time::Instant,
 };
 
 use anyhow::{Context, Result};
-use cargo_metadata::{camino::Utf8PathBuf, semver::Version, Artifact, Message};
+use cargo_metadata::{
+    camino::{Utf8Path, Utf8PathBuf},
+    semver::Version,
+    Artifact, Message,
+};
 
-use crate::shell::Shell;
+use crate::{
+    meta::Target,
+    process::{Group, Watchdog},
+    shell::{ColorChoice, Shell, Verbosity},
+};
 
 #[cfg(target_os = "macos")]
 const ARCH: &str = "darwin-x86_64";
@@ -34,6 +44,48 @@ compile_error!("Unsupported target OS");
 #[cfg(not(any(target_os = "macos", target_os = "linux", target_os = "windows")))]
 const ARCH: &str = "unknown";
 
+/// The name of the NDK's prebuilt toolchain directory for this host, such as `linux-x86_64`.
+///
+/// NDKs only ship some host architectures (e.g. `darwin-x86_64` as a universal binary, and
+/// no `windows-arm64`), so this picks the directory for the host's architecture if it exists,
+/// then the x86_64 one on ARM hosts which can emulate it, and otherwise [`ARCH`].
+pub(crate) fn host_tag(ndk_home: &Path) -> String {
+    best_host_tag(&prebuilt_dirs(ndk_home), env::consts::OS, env::consts::ARCH)
+        .unwrap_or_else(|| ARCH.to_string())
+}
+
+/// The names of the prebuilt toolchain directories in the NDK, one per supported host.
+pub(crate) fn prebuilt_dirs(ndk_home: &Path) -> Vec<String> {
+    let prebuilt = ndk_home.join("toolchains").join("llvm").join("prebuilt");
+    let mut names = std::fs::read_dir(prebuilt)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .filter(|entry| entry.path().is_dir())
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+fn best_host_tag(names: &[String], os: &str, arch: &str) -> Option<String> {
+    let os = match os {
+        "macos" => "darwin",
+        os => os,
+    };
+    let archs: &[&str] = match arch {
+        "aarch64" => &["arm64", "aarch64", "x86_64"],
+        arch => &[arch],
+    };
+
+    archs
+        .iter()
+        .map(|arch| format!("{os}-{arch}"))
+        .find(|tag| names.contains(tag))
+}
+
 pub(crate) fn clang_target(rust_target: &str, api_level: u8) -> String {
     let target = match rust_target {
         "arm-linux-androideabi" => "armv7a-linux-androideabi",
@@ -56,6 +108,18 @@ fn ndk_tool(arch: &str, tool: &str) -> PathBuf {
         .collect()
 }
 
+/// The file name of a binutils-style tool (`ar`, `ranlib`, `strip`) in the NDK.
+///
+/// NDK r23 removed the GNU binutils, leaving only the `llvm-` prefixed tools. Older NDKs are
+/// only guaranteed to have the per-target GNU names, e.g. `aarch64-linux-android-ar`.
+fn binutils_tool(triple: &str, tool: &str, legacy_toolchain: bool) -> String {
+    if legacy_toolchain {
+        format!("{}-{tool}", sysroot_target(triple))
+    } else {
+        format!("llvm-{tool}")
+    }
+}
+
 fn sysroot_suffix(arch: &str) -> PathBuf {
     ["toolchains", "llvm", "prebuilt", arch, "sysroot"]
         .iter()
@@ -66,13 +130,261 @@ fn cargo_env_target_cfg(triple: &str, key: &str) -> String {
     format!("CARGO_TARGET_{}_{}", &triple.replace('-', "_"), key).to_uppercase()
 }
 
+/// The cargo config files that apply to a build run in `dir`, most specific first, as cargo finds
+/// them: in `.cargo` in `dir` and each of its parents, then in `CARGO_HOME`.
+fn cargo_config_files(dir: &Path) -> Vec<PathBuf> {
+    #[allow(deprecated)] // For reading HOME on Windows too, which only the likes of MSYS set
+    let cargo_home = env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::home_dir().map(|home| home.join(".cargo")));
+
+    let mut files = Vec::new();
+    for config_dir in dir
+        .ancestors()
+        .map(|dir| dir.join(".cargo"))
+        .chain(cargo_home)
+    {
+        // Cargo prefers the older name without the extension when both exist
+        let file = ["config", "config.toml"]
+            .iter()
+            .map(|name| config_dir.join(name))
+            .find(|file| file.is_file());
+        if let Some(file) = file.filter(|file| !files.contains(file)) {
+            files.push(file);
+        }
+    }
+    files
+}
+
+/// The values of a dotted `key`, such as `target.<triple>.runner`, in the cargo config files that
+/// apply to a build run in `dir`, most specific first. Values given on the command line with
+/// `--config` aren't included.
+fn cargo_config_values(dir: &Path, key: &[&str]) -> Vec<toml::Value> {
+    cargo_config_files(dir)
+        .iter()
+        .filter_map(|file| std::fs::read_to_string(file).ok())
+        .filter_map(|config| config.parse::<toml::Table>().ok())
+        .filter_map(|config| {
+            let (first, rest) = key.split_first()?;
+            rest.iter()
+                .try_fold(config.get(*first)?, |value, key| value.get(key))
+                .cloned()
+        })
+        .collect()
+}
+
+/// Replace the backslashes in `value` with forward slashes for MSYS and Cygwin, leaving anything
+/// that isn't valid UTF-8 intact.
+fn forward_slashes(value: OsString) -> OsString {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+        let bytes = value
+            .as_bytes()
+            .iter()
+            .map(|&b| if b == b'\\' { b'/' } else { b });
+        OsString::from_vec(bytes.collect())
+    }
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::{OsStrExt, OsStringExt};
+        let wide = value
+            .encode_wide()
+            .map(|c| {
+                if c == u16::from(b'\\') {
+                    u16::from(b'/')
+                } else {
+                    c
+                }
+            })
+            .collect::<Vec<_>>();
+        OsString::from_wide(&wide)
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    {
+        match value.into_string() {
+            Ok(value) => value.replace('\\', "/").into(),
+            Err(value) => value,
+        }
+    }
+}
+
+/// Whether the cargo command, the first of `cargo_args`, runs the binaries it builds.
+pub(crate) fn runs_binaries(cargo_args: &[String]) -> bool {
+    matches!(
+        cargo_args.first().map(String::as_str),
+        Some("test" | "t" | "bench" | "run" | "r")
+    )
+}
+
+/// Whether the cargo command, the first of `cargo_args`, builds test binaries without running them.
+pub(crate) fn only_builds_tests(cargo_args: &[String]) -> bool {
+    matches!(
+        cargo_args.first().map(String::as_str),
+        Some("test" | "t" | "bench")
+    ) && cargo_args
+        .iter()
+        .take_while(|arg| *arg != "--")
+        .any(|arg| arg == "--no-run")
+}
+
+/// Whether there's a runner for `triple` already, which cargo-ndk's own would take priority over:
+/// in the environment, or in cargo's config for a build run in `dir`, either under the triple or
+/// a `cfg(...)` that matches it.
+fn has_configured_runner(triple: &str, dir: &Path) -> bool {
+    env::var_os(cargo_env_target_cfg(triple, "runner")).is_some()
+        || cargo_config_values(dir, &["target"]).iter().any(|targets| {
+            targets.as_table().is_some_and(|targets| {
+                targets.iter().any(|(key, target)| {
+                    (key == triple || cfg_matches(key, triple)) && target.get("runner").is_some()
+                })
+            })
+        })
+}
+
+/// The linker already set for `triple`, which cargo-ndk's own would take priority over: in the
+/// environment, or in cargo's config for a build run in `dir`.
+fn configured_linker(triple: &str, dir: &Path) -> Option<String> {
+    let linker_key = cargo_env_target_cfg(triple, "linker");
+    if let Some(linker) = env::var_os(&linker_key) {
+        return Some(format!("{linker_key}={linker:?}"));
+    }
+    cargo_config_values(dir, &["target", triple, "linker"])
+        .first()
+        .map(|linker| format!("target.{triple}.linker = {linker}"))
+}
+
+/// The `cfg` options rustc sets for an Android target, as far as `cfg(...)` tables in cargo's
+/// config are likely to ask about them.
+fn target_cfg(triple: &str) -> Vec<(&'static str, Option<&str>)> {
+    let (arch, pointer_width) = match triple.split('-').next().unwrap_or_default() {
+        "armv7" | "arm" | "thumbv7neon" => ("arm", "32"),
+        "i686" => ("x86", "32"),
+        arch => (arch, "64"),
+    };
+    vec![
+        ("unix", None),
+        ("target_family", Some("unix")),
+        ("target_os", Some("android")),
+        ("target_env", Some("")),
+        ("target_vendor", Some("unknown")),
+        ("target_endian", Some("little")),
+        ("target_arch", Some(arch)),
+        ("target_pointer_width", Some(pointer_width)),
+        (
+            "target_abi",
+            Some(if triple.ends_with("eabi") { "eabi" } else { "" }),
+        ),
+    ]
+}
+
+#[derive(Debug, PartialEq)]
+enum CfgToken<'a> {
+    Ident(&'a str),
+    Str(&'a str),
+    Open,
+    Close,
+    Comma,
+    Eq,
+}
+
+fn cfg_tokens(expr: &str) -> Option<Vec<CfgToken<'_>>> {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+    let mut tokens = Vec::new();
+    let mut rest = expr.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let (token, len) = match c {
+            '(' => (CfgToken::Open, 1),
+            ')' => (CfgToken::Close, 1),
+            ',' => (CfgToken::Comma, 1),
+            '=' => (CfgToken::Eq, 1),
+            '"' => {
+                let end = rest[1..].find('"')? + 1;
+                (CfgToken::Str(&rest[1..end]), end + 1)
+            }
+            c if is_ident(c) => {
+                let end = rest.find(|c| !is_ident(c)).unwrap_or(rest.len());
+                (CfgToken::Ident(&rest[..end]), end)
+            }
+            _ => return None,
+        };
+        tokens.push(token);
+        rest = rest[len..].trim_start();
+    }
+    Some(tokens)
+}
+
+fn eval_cfg<'a>(
+    tokens: &mut std::iter::Peekable<std::vec::IntoIter<CfgToken<'a>>>,
+    cfg: &[(&str, Option<&str>)],
+) -> Option<bool> {
+    let CfgToken::Ident(name) = tokens.next()? else {
+        return None;
+    };
+    match tokens.peek() {
+        Some(CfgToken::Open) => {
+            tokens.next();
+            let mut values = Vec::new();
+            loop {
+                if tokens.peek() == Some(&CfgToken::Close) {
+                    tokens.next();
+                    break;
+                }
+                values.push(eval_cfg(tokens, cfg)?);
+                match tokens.next()? {
+                    CfgToken::Comma => {}
+                    CfgToken::Close => break,
+                    _ => return None,
+                }
+            }
+            match name {
+                "all" => Some(values.iter().all(|value| *value)),
+                "any" => Some(values.iter().any(|value| *value)),
+                "not" if values.len() == 1 => Some(!values[0]),
+                _ => None,
+            }
+        }
+        Some(CfgToken::Eq) => {
+            tokens.next();
+            let CfgToken::Str(value) = tokens.next()? else {
+                return None;
+            };
+            Some(cfg.iter().any(|(k, v)| *k == name && *v == Some(value)))
+        }
+        _ => Some(cfg.iter().any(|(k, v)| *k == name && v.is_none())),
+    }
+}
+
+/// Whether a `cfg(...)` key of a `[target]` table in cargo's config applies to an Android
+/// `triple`. One that can't be parsed doesn't.
+fn cfg_matches(key: &str, triple: &str) -> bool {
+    let Some(expr) = key
+        .trim()
+        .strip_prefix("cfg(")
+        .and_then(|expr| expr.strip_suffix(')'))
+    else {
+        return false;
+    };
+    let Some(tokens) = cfg_tokens(expr) else {
+        return false;
+    };
+    let mut tokens = tokens.into_iter().peekable();
+    eval_cfg(&mut tokens, &target_cfg(triple)) == Some(true) && tokens.next().is_none()
+}
+
 #[inline]
 fn env_var_with_key(key: String) -> Option<(String, String)> {
     env::var(&key).map(|value| (key, value)).ok()
 }
 
 // Derived from getenv_with_target_prefixes in `cc` crate.
-fn cc_env(var_base: &str, triple: &str) -> (String, Option<String>) {
+//
+// The key returned is where cargo-ndk sets the variable, so it's never a plain `CC`, `CFLAGS` and
+// so on: the `cc` crate also uses those for build scripts and other code built for the host, which
+// has to keep using the host's compiler. A plain one that's set still provides the value.
+pub(crate) fn cc_env(var_base: &str, triple: &str) -> (String, Option<String>) {
     let triple_u = triple.replace('-', "_");
     let most_specific_key = format!("{}_{}", var_base, triple);
 
@@ -80,21 +392,383 @@ fn cc_env(var_base: &str, triple: &str) -> (String, Option<String>) {
         .or_else(|| env_var_with_key(format!("{}_{}", var_base, triple_u)))
         .or_else(|| env_var_with_key(format!("TARGET_{}", var_base)))
         .or_else(|| env_var_with_key(var_base.to_string()))
-        .map(|(key, value)| (key, Some(value)))
+        .map(|(key, value)| {
+            let key = if key == var_base {
+                most_specific_key.clone()
+            } else {
+                key
+            };
+            (key, Some(value))
+        })
         .unwrap_or_else(|| (most_specific_key, None))
 }
 
+/// The directory containing the running cargo-ndk binary, and so its sibling binaries.
+///
+/// This is resolved from the running executable rather than `argv[0]`, so the linker wrapper
+/// that cargo is pointed at is this same build of cargo-ndk and not a stale copy elsewhere.
+pub(crate) fn self_dir() -> PathBuf {
+    env::current_exe()
+        .and_then(std::fs::canonicalize)
+        .or_else(|_| std::fs::canonicalize(env::args().next().unwrap()))
+        .expect("Failed to canonicalize absolute path to cargo-ndk")
+        .parent()
+        .unwrap()
+        .to_path_buf()
+}
+
+/// Warn if any of the binaries that cargo-ndk points cargo at aren't installed alongside the
+/// running binary, as cargo would then fail with an unhelpful error, or a stale copy be used.
+pub(crate) fn check_sibling_binaries(shell: &mut Shell) -> Result<()> {
+    let self_dir = self_dir();
+    for name in ["cargo-ndk", "cargo-ndk-runner"] {
+        let path = self_dir.join(format!("{name}{}", env::consts::EXE_SUFFIX));
+        if !path.is_file() {
+            shell.warn(format!(
+                "{} was not found next to this binary; reinstall cargo-ndk",
+                path.display()
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+/// The CPU and features to generate code for, from `--target-cpu` and `--target-feature`. They
+/// are given to both rustc and clang, as code built for different features by each may not work
+/// together.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TargetCpu {
+    pub cpu: Option<String>,
+    /// Features in LLVM's `+feature`/`-feature` form, which rustc and clang both understand.
+    pub features: Vec<String>,
+}
+
+impl TargetCpu {
+    fn rustflags(&self) -> Vec<String> {
+        let mut flags = Vec::new();
+        if let Some(cpu) = &self.cpu {
+            flags.push(format!("-Ctarget-cpu={cpu}"));
+        }
+        if !self.features.is_empty() {
+            flags.push(format!("-Ctarget-feature={}", self.features.join(",")));
+        }
+        flags
+    }
+
+    fn clang_flags(&self, triple: &str) -> Vec<String> {
+        let mut flags = Vec::new();
+        if let Some(cpu) = &self.cpu {
+            // clang's -mcpu only tunes for x86 rather than choosing the instruction set
+            if triple.starts_with("i686") || triple.starts_with("x86_64") {
+                flags.push(format!("-march={cpu}"));
+            } else {
+                flags.push(format!("-mcpu={cpu}"));
+            }
+        }
+        for feature in &self.features {
+            flags.extend(["-Xclang", "-target-feature", "-Xclang"].map(String::from));
+            flags.push(feature.clone());
+        }
+        flags
+    }
+}
+
+/// Features compared between rustc and clang by [`check_cpu_flags`]. Only these are looked for
+/// in clang's `-m` flags, as most of those aren't features at all.
+const CHECKED_FEATURES: &[&str] = &[
+    "neon", "crypto", "aes", "sha2", "dotprod", "i8mm", "crc", "lse", "rcpc", "fp16", "sve",
+    "sve2", "sse4.1", "sse4.2", "popcnt", "avx", "avx2", "fma", "bmi1", "bmi2",
+];
+
+/// The CPU and features asked of a compiler, as far as they can be told from its flags.
+#[derive(Debug, Default)]
+struct CpuFlags {
+    cpu: Option<String>,
+    features: BTreeMap<String, bool>,
+}
+
+impl CpuFlags {
+    fn set_feature(&mut self, feature: &str) {
+        let (name, enabled) = match feature.strip_prefix('-') {
+            Some(name) => (name, false),
+            None => (feature.trim_start_matches('+'), true),
+        };
+        // clang's "crypto" is the combination of what rustc calls "aes" and "sha2"
+        let names: &[&str] = if name == "crypto" {
+            &["aes", "sha2"]
+        } else {
+            &[name]
+        };
+        for name in names {
+            if CHECKED_FEATURES.contains(name) {
+                self.features.insert(name.to_string(), enabled);
+            }
+        }
+    }
+
+    fn from_rustflags(flags: &[String]) -> Self {
+        let mut parsed = Self::default();
+        let mut flags = flags.iter().map(String::as_str);
+        while let Some(flag) = flags.next() {
+            let codegen = match flag {
+                "-C" | "--codegen" => flags.next().unwrap_or_default(),
+                _ => match flag.strip_prefix("-C") {
+                    Some(codegen) => codegen,
+                    None => continue,
+                },
+            };
+            if let Some(cpu) = codegen.strip_prefix("target-cpu=") {
+                parsed.cpu = Some(cpu.to_string());
+            } else if let Some(features) = codegen.strip_prefix("target-feature=") {
+                features.split(',').for_each(|f| parsed.set_feature(f));
+            }
+        }
+        parsed
+    }
+
+    fn from_cflags(triple: &str, flags: &str) -> Self {
+        let x86 = triple.starts_with("i686") || triple.starts_with("x86_64");
+        let mut parsed = Self::default();
+        let flags = flags.split_whitespace().collect::<Vec<_>>();
+        for (i, flag) in flags.iter().enumerate() {
+            if let Some(cpu) = flag.strip_prefix("-mcpu=") {
+                // An ARM CPU can also have features after it, like an architecture
+                let mut parts = cpu.split('+');
+                parsed.cpu = parts.next().map(str::to_string);
+                for feature in parts {
+                    match feature.strip_prefix("no") {
+                        Some(name) => parsed.set_feature(&format!("-{name}")),
+                        None => parsed.set_feature(feature),
+                    }
+                }
+            } else if let Some(arch) = flag.strip_prefix("-march=") {
+                let mut parts = arch.split('+');
+                let arch = parts.next().unwrap_or_default();
+                // x86 CPUs are chosen with -march, whereas ARM's is an architecture version
+                if x86 {
+                    parsed.cpu = Some(arch.to_string());
+                }
+                for feature in parts {
+                    match feature.strip_prefix("no") {
+                        Some(name) => parsed.set_feature(&format!("-{name}")),
+                        None => parsed.set_feature(feature),
+                    }
+                }
+            } else if let Some(fpu) = flag.strip_prefix("-mfpu=") {
+                if fpu.contains("neon") {
+                    parsed.set_feature("neon");
+                }
+                if fpu.starts_with("crypto") {
+                    parsed.set_feature("crypto");
+                }
+            } else if *flag == "-target-feature" && i > 0 && flags[i - 1] == "-Xclang" {
+                if let Some(feature) = flags.get(i + 2) {
+                    parsed.set_feature(feature);
+                }
+            } else if let Some(name) = flag.strip_prefix("-mno-") {
+                parsed.set_feature(&format!("-{name}"));
+            } else if let Some(name) = flag.strip_prefix("-m") {
+                parsed.set_feature(name);
+            }
+        }
+        parsed
+    }
+}
+
+/// The rustflags in the environment that cargo will use for `triple`. Those in cargo's config
+/// files can't be seen from here.
+fn env_rustflags(triple: &str) -> Vec<String> {
+    if let Ok(encoded) = env::var("CARGO_ENCODED_RUSTFLAGS") {
+        return encoded
+            .split('\x1f')
+            .filter(|x| !x.is_empty())
+            .map(str::to_string)
+            .collect();
+    }
+
+    [
+        "RUSTFLAGS".to_string(),
+        cargo_env_target_cfg(triple, "rustflags"),
+        "CARGO_BUILD_RUSTFLAGS".to_string(),
+    ]
+    .iter()
+    .find_map(|key| env::var(key).ok())
+    .map(|flags| flags.split_whitespace().map(str::to_string).collect())
+    .unwrap_or_default()
+}
+
+/// The rustflags in config values from [`cargo_config_values`], each either a string of
+/// space-separated flags or an array of them. Like cargo, arrays from several files are joined,
+/// with those from the more specific files last.
+fn config_rustflags(values: &[toml::Value]) -> Vec<String> {
+    values
+        .iter()
+        .rev()
+        .flat_map(|value| match value {
+            toml::Value::String(flags) => flags.split_whitespace().map(str::to_string).collect(),
+            toml::Value::Array(flags) => flags
+                .iter()
+                .filter_map(toml::Value::as_str)
+                .map(str::to_string)
+                .collect(),
+            _ => Vec::new(),
+        })
+        .collect()
+}
+
+/// Warn about obvious differences between the CPU and features that rustc and clang are asked to
+/// generate code for, as Rust and C code built for different ones may not work together.
+///
+/// This is only a heuristic: a CPU implies features of its own, so a feature missing on one side
+/// is only reported when that side has no CPU set either.
+fn check_cpu_flags(
+    shell: &mut Shell,
+    triple: &str,
+    rustflags: &[String],
+    cflags: &str,
+) -> Result<()> {
+    let rust = CpuFlags::from_rustflags(rustflags);
+    let c = CpuFlags::from_cflags(triple, cflags);
+    let mut mismatched = false;
+
+    match (&rust.cpu, &c.cpu) {
+        (Some(rust_cpu), Some(c_cpu)) if rust_cpu != c_cpu => {
+            shell.warn(format!(
+                "rustc generates code for the {rust_cpu} CPU, but C code for {c_cpu}"
+            ))?;
+            mismatched = true;
+        }
+        (Some(cpu), None) => {
+            shell.warn(format!(
+                "rustc generates code for the {cpu} CPU, but C code doesn't"
+            ))?;
+            mismatched = true;
+        }
+        (None, Some(cpu)) => {
+            shell.warn(format!(
+                "C code is compiled for the {cpu} CPU, but rustc doesn't generate code for it"
+            ))?;
+            mismatched = true;
+        }
+        _ => {}
+    }
+
+    for feature in CHECKED_FEATURES {
+        let (rust_enabled, c_enabled) = (rust.features.get(*feature), c.features.get(*feature));
+        let differs = match (rust_enabled, c_enabled) {
+            (Some(a), Some(b)) => a != b,
+            (Some(true), None) => c.cpu.is_none(),
+            (None, Some(true)) => rust.cpu.is_none(),
+            _ => false,
+        };
+        if differs {
+            let state = |enabled: Option<&bool>| match enabled {
+                Some(true) => "enabled",
+                Some(false) => "disabled",
+                None => "not set",
+            };
+            shell.warn(format!(
+                "the {feature} feature is {} for rustc, but {} for C code",
+                state(rust_enabled),
+                state(c_enabled),
+            ))?;
+            mismatched = true;
+        }
+    }
+
+    if mismatched {
+        shell
+            .note("use --target-cpu and --target-feature to give both the same CPU and features")?;
+    }
+    Ok(())
+}
+
+/// The API level in a clang `--target=<triple><level>` flag, if `flags` has one.
+fn clang_target_platform(flags: &str) -> Option<u8> {
+    let target = flags
+        .split_whitespace()
+        .filter_map(|flag| flag.strip_prefix("--target="))
+        .next_back()?;
+    let level = &target[target.trim_end_matches(|c: char| c.is_ascii_digit()).len()..];
+    level.parse().ok()
+}
+
+/// Warn if the environment was set up by `cargo ndk-env` for a different API level than the one
+/// being built for, as in `source <(cargo ndk-env -p 24)` followed by `cargo ndk -p 21 build`.
+/// Each target is given with the API level it's built for.
+///
+/// Most of what `ndk-env` exports is replaced for the build, but C flags are added to rather than
+/// replaced, and the exported `--target` comes last, so it wins over cargo-ndk's own.
+pub(crate) fn check_exported_platform(shell: &mut Shell, targets: &[(&str, u8)]) -> Result<()> {
+    // The API level of the first target that doesn't match, to suggest re-running ndk-env with
+    let mut mismatched = None;
+
+    for &(triple, platform) in targets {
+        for base in ["CFLAGS", "CXXFLAGS"] {
+            let (key, value) = cc_env(base, triple);
+            let Some(exported) = value.as_deref().and_then(clang_target_platform) else {
+                continue;
+            };
+            if exported != platform {
+                shell.warn(format!(
+                    "{key} targets API level {exported}, but the build is for API level {platform}, so C code for {triple} will be built for API level {exported}"
+                ))?;
+                mismatched.get_or_insert(platform);
+            }
+        }
+    }
+
+    if mismatched.is_none() && env::var_os("CARGO_NDK_SYSROOT_PATH").is_some() {
+        let exported = env::var("CARGO_NDK_ANDROID_PLATFORM")
+            .ok()
+            .and_then(|level| level.parse::<u8>().ok());
+        if let Some(exported) = exported {
+            if let Some(&(_, platform)) = targets.iter().find(|(_, p)| *p != exported) {
+                shell.warn(format!(
+                    "the environment was set up by `cargo ndk-env` for API level {exported}, but the build is for API level {platform}"
+                ))?;
+                mismatched = Some(platform);
+            }
+        }
+    }
+
+    if let Some(platform) = mismatched {
+        shell.note(format!(
+            "re-run `cargo ndk-env --platform {platform}`, or start from a shell without its variables"
+        ))?;
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            triple = %triple,
+            platform,
+            ndk_home = %ndk_home.display(),
+        )
+    )
+)]
 pub(crate) fn build_env(
     triple: &str,
     ndk_home: &Path,
-    clang_target: &str,
+    platform: u8,
     bindgen: bool,
+    legacy_toolchain: bool,
+    pkg_config: bool,
+    openssl_dir: Option<&Path>,
+    linker_override: bool,
+    link_libs: &[String],
+    target_cpu: &TargetCpu,
 ) -> BTreeMap<String, OsString> {
-    let self_path = std::fs::canonicalize(env::args().next().unwrap())
-        .expect("Failed to canonicalize absolute path to cargo-ndk")
-        .parent()
-        .unwrap()
-        .join("cargo-ndk");
+    let self_path = self_dir().join("cargo-ndk");
+    // The API level is encoded in clang's `--target` as well as given to the likes of cmake, so
+    // both come from the one platform to keep them in agreement.
+    let clang_target = clang_target(triple, platform);
 
     // Environment variables for the `cc` crate
     let (cc_key, _cc_value) = cc_env("CC", triple);
@@ -108,19 +782,26 @@ pub(crate) fn build_env(
     let cargo_ar_key = cargo_env_target_cfg(triple, "ar");
     let cargo_linker_key = cargo_env_target_cfg(triple, "linker");
     let bindgen_clang_args_key = format!("BINDGEN_EXTRA_CLANG_ARGS_{}", &triple.replace('-', "_"));
-    
-    let target_cc = ndk_home.join(ndk_tool(ARCH, "clang"));
-    let target_cflags = match cflags_value {
-        Some(v) => format!("{clang_target} {v}"),
-        None => clang_target.to_string(),
-    };
-    let target_cxx = ndk_home.join(ndk_tool(ARCH, "clang++"));
-    let target_cxxflags = match cxxflags_value {
-        Some(v) => format!("{clang_target} {v}"),
-        None => clang_target.to_string(),
-    };
+
+    let host = host_tag(ndk_home);
+    let target_cc = ndk_home.join(ndk_tool(&host, "clang"));
+    // The user's own flags go last, so they can override cargo-ndk's
+    let cpu_cflags = target_cpu.clang_flags(triple);
+    let target_cflags = [clang_target.to_string()]
+        .into_iter()
+        .chain(cpu_cflags.iter().cloned())
+        .chain(cflags_value)
+        .collect::<Vec<_>>()
+        .join(" ");
+    let target_cxx = ndk_home.join(ndk_tool(&host, "clang++"));
+    let target_cxxflags = [clang_target.to_string()]
+        .into_iter()
+        .chain(cpu_cflags)
+        .chain(cxxflags_value)
+        .collect::<Vec<_>>()
+        .join(" ");
     let cargo_ndk_sysroot_path_key = "CARGO_NDK_SYSROOT_PATH";
-    let cargo_ndk_sysroot_path = ndk_home.join(sysroot_suffix(ARCH));
+    let cargo_ndk_sysroot_path = ndk_home.join(sysroot_suffix(&host));
     let cargo_ndk_sysroot_target_key = "CARGO_NDK_SYSROOT_TARGET";
     let cargo_ndk_sysroot_target = sysroot_target(triple);
     let cargo_ndk_sysroot_libs_path_key = "CARGO_NDK_SYSROOT_LIBS_PATH";
@@ -128,8 +809,14 @@ pub(crate) fn build_env(
         .join("usr")
         .join("lib")
         .join(cargo_ndk_sysroot_target);
-    let target_ar = ndk_home.join(ndk_tool(ARCH, "llvm-ar"));
-    let target_ranlib = ndk_home.join(ndk_tool(ARCH, "llvm-ranlib"));
+    let target_ar = ndk_home.join(ndk_tool(
+        &host,
+        &binutils_tool(triple, "ar", legacy_toolchain),
+    ));
+    let target_ranlib = ndk_home.join(ndk_tool(
+        &host,
+        &binutils_tool(triple, "ranlib", legacy_toolchain),
+    ));
     let target_linker = self_path;
 
     let extra_include = format!(
@@ -146,7 +833,6 @@ pub(crate) fn build_env(
         (ar_key, target_ar.clone().into()),
         (ranlib_key, target_ranlib.into_os_string()),
         (cargo_ar_key, target_ar.into_os_string()),
-        (cargo_linker_key, target_linker.into_os_string()),
         (
             cargo_ndk_sysroot_path_key.to_string(),
             cargo_ndk_sysroot_path.clone().into_os_string(),
@@ -159,38 +845,116 @@ pub(crate) fn build_env(
             cargo_ndk_sysroot_target_key.to_string(),
             cargo_ndk_sysroot_target.into(),
         ),
+        (
+            "CARGO_NDK_CMAKE_TOOLCHAIN_PATH".into(),
+            ndk_home
+                .join("build")
+                .join("cmake")
+                .join("android.toolchain.cmake")
+                .into_os_string(),
+        ),
+        (
+            "CARGO_NDK_ANDROID_PLATFORM".into(),
+            platform.to_string().into(),
+        ),
+        // Read by the NDK's cmake toolchain file, and so by the `cmake` crate
+        ("ANDROID_PLATFORM".into(), platform.to_string().into()),
+        (
+            "ANDROID_NATIVE_API_LEVEL".into(),
+            platform.to_string().into(),
+        ),
         // Found this through a comment related to bindgen using the wrong clang for cross compiles
         //
         // https://github.com/rust-lang/rust-bindgen/issues/2962#issuecomment-2438297124
         //
         // https://github.com/KyleMayes/clang-sys?tab=readme-ov-file#environment-variables
         ("CLANG_PATH".into(), target_cc.with_extension("exe").into()),
-
-        ("_CARGO_NDK_LINK_TARGET".into(), clang_target.into()), // Recognized by main() so we know when we're acting as a wrapper
-        ("_CARGO_NDK_LINK_CLANG".into(), target_cc.into()),
     ]
     .into_iter()
     .collect::<BTreeMap<String, OsString>>();
 
+    if let Ok(target) = triple.parse::<Target>() {
+        envs.insert("CARGO_NDK_ANDROID_TARGET".into(), target.to_string().into());
+        envs.insert("ANDROID_ABI".into(), target.to_string().into());
+    }
+
+    // Link through cargo-ndk itself, unless the user has asked to use their own linker.
+    if linker_override {
+        envs.insert(cargo_linker_key, target_linker.into_os_string());
+        envs.insert("_CARGO_NDK_LINK_TARGET".into(), clang_target.into()); // Recognized by main() so we know when we're acting as a wrapper
+        envs.insert("_CARGO_NDK_LINK_CLANG".into(), target_cc.into_os_string());
+        // Libraries from `--link-lib`, which the wrapper adds a `-l` for each of
+        if !link_libs.is_empty() {
+            envs.insert("_CARGO_NDK_LINK_LIBS".into(), link_libs.join(" ").into());
+        }
+    }
+
+    // Environment variables for the `pkg-config` crate, which refuses to run when cross
+    // compiling unless told otherwise. They're target-specific so host build scripts are unaffected.
+    if pkg_config {
+        let triple_u = triple.replace('-', "_");
+        envs.insert(format!("PKG_CONFIG_ALLOW_CROSS_{triple_u}"), "1".into());
+        envs.insert(
+            format!("PKG_CONFIG_SYSROOT_DIR_{triple_u}"),
+            cargo_ndk_sysroot_path.clone().into_os_string(),
+        );
+
+        let pkg_config_path = cargo_ndk_sysroot_path
+            .join("usr")
+            .join("lib")
+            .join(cargo_ndk_sysroot_target)
+            .join("pkgconfig");
+        if pkg_config_path.is_dir() {
+            envs.insert(
+                format!("PKG_CONFIG_PATH_{triple_u}"),
+                pkg_config_path.into_os_string(),
+            );
+        }
+    }
+
+    // Environment variables for the `openssl-sys` crate, which prefixes its variables with the
+    // upper-cased target rather than suffixing them like `cc` does.
+    if let Some(openssl_dir) = openssl_dir {
+        let prefix = triple.replace('-', "_").to_uppercase();
+        envs.insert(
+            format!("{prefix}_OPENSSL_DIR"),
+            openssl_dir.as_os_str().to_os_string(),
+        );
+        envs.insert(
+            format!("{prefix}_OPENSSL_INCLUDE_DIR"),
+            openssl_dir.join("include").into_os_string(),
+        );
+        envs.insert(
+            format!("{prefix}_OPENSSL_LIB_DIR"),
+            openssl_dir.join("lib").into_os_string(),
+        );
+    }
+
     if env::var("MSYSTEM").is_ok() || env::var("CYGWIN").is_ok() {
         envs = envs
             .into_iter()
-            .map(|(k, v)| {
-                (
-                    k,
-                    OsString::from(v.into_string().unwrap().replace('\\', "/")),
-                )
-            })
+            .map(|(k, v)| (k, forward_slashes(v)))
             .collect();
     }
 
     if bindgen {
-        let bindgen_args = format!(
-            "--sysroot={} -I{}",
-            &cargo_ndk_sysroot_path.display(),
-            extra_include
-        );
-        let bindgen_clang_args = bindgen_args.replace('\\', "/");
+        // bindgen splits these like a shell would, so paths with spaces (e.g. under
+        // `C:\Program Files`) must be quoted to survive as one argument each.
+        let bindgen_args = [
+            format!("--sysroot={}", cargo_ndk_sysroot_path.display()),
+            format!("-I{extra_include}"),
+        ]
+        .iter()
+        .map(|arg| arg.replace('\\', "/"))
+        .map(|arg| {
+            if arg.contains(char::is_whitespace) {
+                format!("\"{arg}\"")
+            } else {
+                arg
+            }
+        })
+        .collect::<Vec<_>>();
+        let bindgen_clang_args = bindgen_args.join(" ");
         // log::debug!("{bindgen_clang_args_key}={bindgen_clang_args:?}");
         envs.insert(
             bindgen_clang_args_key.to_string(),
@@ -213,8 +977,22 @@ pub(crate) fn build_env(
 /// configured for the project and there's no practical way to read all
 /// user-configured rustflags from outside of cargo itself.
 #[allow(clippy::too_many_arguments)]
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        level = "info",
+        skip_all,
+        fields(
+            triple = %triple,
+            platform = platform,
+            ndk_version = %version,
+            ndk_home = %ndk_home.display(),
+        )
+    )
+)]
 pub(crate) fn run(
     shell: &mut Shell,
+    cargo_bin: &Path,
     dir: &Path,
     ndk_home: &Path,
     version: &Version,
@@ -223,12 +1001,28 @@ pub(crate) fn run(
     cargo_args: &[String],
     cargo_manifest: &Path,
     bindgen: bool,
+    legacy_toolchain: bool,
+    pkg_config: bool,
+    openssl_dir: Option<&Path>,
+    linker_override: bool,
+    link_libs: &[String],
+    target_cpu: &TargetCpu,
+    extra_env: &[(String, String)],
+    print_cargo_cmd: bool,
+    json_lines: bool,
+    verbose_cc: bool,
+    passthrough_output: bool,
+    deadline: Option<Instant>,
     #[allow(unused_variables)] out_dir: &Utf8PathBuf,
 ) -> Result<(std::process::ExitStatus, Vec<Artifact>)> {
-    if version.major < 23 {
-        shell.error("NDK versions less than r23 are not supported. Install an up-to-date version of the NDK.").unwrap();
-        std::process::exit(1);
-    }
+    // cargo-ndk needs cargo's JSON messages to find the artifacts, so the user's own
+    // `--message-format` is taken out and translated into an equivalent that includes them.
+    let user_cargo_args = cargo_args;
+    let (cargo_args, user_message_format) = take_message_format(cargo_args);
+    let json_lines = json_lines
+        || user_message_format
+            .iter()
+            .any(|format| format.starts_with("json"));
 
     // Insert Cargo arguments before any `--` arguments.
     let arg_insertion_position = cargo_args
@@ -239,10 +1033,37 @@ pub(crate) fn run(
 
     let mut cargo_args: Vec<OsString> = cargo_args.iter().map(Into::into).collect();
 
-    let clang_target = clang_target(triple, platform);
-    let cargo_bin = env::var("CARGO").unwrap_or_else(|_| "cargo".into());
-    let mut cargo_cmd = Command::new(&cargo_bin);
-    let envs = build_env(triple, ndk_home, &clang_target, bindgen);
+    let mut cargo_cmd = Command::new(cargo_bin);
+    let mut envs = build_env(
+        triple,
+        ndk_home,
+        platform,
+        bindgen,
+        legacy_toolchain,
+        pkg_config,
+        openssl_dir,
+        linker_override,
+        link_libs,
+        target_cpu,
+    );
+
+    // Binaries are run on a device with `cargo-ndk-runner`, for the commands that run any, unless
+    // the user has already set up their own runner for the target.
+    if runs_binaries(user_cargo_args) && !has_configured_runner(triple, dir) {
+        envs.insert(
+            cargo_env_target_cfg(triple, "runner"),
+            self_dir().join("cargo-ndk-runner").into_os_string(),
+        );
+    }
+
+    if linker_override {
+        if let Some(linker) = configured_linker(triple, dir) {
+            shell.warn(format!(
+                "{linker} is overridden by cargo-ndk's own linker wrapper"
+            ))?;
+            shell.note("pass --no-linker-override to use it instead")?;
+        }
+    }
 
     shell
         .very_verbose(|shell| {
@@ -254,67 +1075,1272 @@ pub(crate) fn run(
                 )?;
             }
 
+            // These are passed through to cargo untouched, as cargo-ndk only wraps the linker.
+            for key in ["RUSTC", "RUSTC_WRAPPER", "RUSTC_WORKSPACE_WRAPPER"] {
+                if let Some(value) = env::var_os(key) {
+                    shell.status_with_color(
+                        "Using",
+                        format!("{key}={value:?}"),
+                        termcolor::Color::Cyan,
+                    )?;
+                }
+            }
+
             shell.status_with_color(
                 "Invoking",
-                format!("cargo ({cargo_bin}) with args: {cargo_args:?}"),
+                format!("cargo ({}) with args: {cargo_args:?}", cargo_bin.display()),
                 termcolor::Color::Cyan,
             )
         })
         .unwrap();
 
-    cargo_cmd.current_dir(dir).envs(envs);
+    shell
+        .verbose(|shell| {
+            for (k, v) in extra_env.iter() {
+                if envs.contains_key(k) {
+                    shell.note(format!("{k}={v:?} overrides the value set by cargo-ndk"))?;
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    cargo_cmd
+        .current_dir(dir)
+        .envs(&envs)
+        .envs(extra_env.iter().map(|(k, v)| (k, v)));
+
+    // Have the linker wrapper print the clang command it runs
+    if shell.verbosity() == Verbosity::VeryVerbose {
+        cargo_cmd.env("_CARGO_NDK_VERBOSE", "1");
+    }
+
+    // cargo finds the manifest in its working directory by itself. Any other one, such as one
+    // picked from `-p`, is passed on, already made absolute so it doesn't matter where cargo runs.
+    if is_found_in(cargo_manifest, dir) {
+        shell.very_verbose(|shell| {
+            shell.status_with_color(
+                "Using",
+                format!(
+                    "{} from the working directory, without --manifest-path",
+                    cargo_manifest.display()
+                ),
+                termcolor::Color::Cyan,
+            )
+        })?;
+    } else {
+        shell.very_verbose(|shell| {
+            shell.status_with_color(
+                "Passing",
+                format!(
+                    "--manifest-path {}, as it isn't in the working directory {}",
+                    cargo_manifest.display(),
+                    dir.display()
+                ),
+                termcolor::Color::Cyan,
+            )
+        })?;
+        cargo_args.insert(arg_insertion_position, cargo_manifest.into());
+        cargo_args.insert(arg_insertion_position, "--manifest-path".into());
+    }
+
+    let (cflags_key, _) = cc_env("CFLAGS", triple);
+    let mut checked_rustflags = env_rustflags(triple);
+    checked_rustflags.extend(target_cpu.rustflags());
+    check_cpu_flags(
+        shell,
+        triple,
+        &checked_rustflags,
+        &envs[&cflags_key].to_string_lossy(),
+    )?;
 
-    match dir.parent() {
-        Some(parent) => {
-            if parent != dir {
-                // log::debug!("Working directory does not match manifest-path");
-                cargo_args.insert(arg_insertion_position, cargo_manifest.into());
-                cargo_args.insert(arg_insertion_position, "--manifest-path".into());
+    // rustc gets the same CPU and features as clang. RUSTFLAGS replaces any configured rustflags,
+    // so they're added to cargo's configuration instead, unless the user already uses RUSTFLAGS.
+    // Either is added to as it is, as flags that aren't valid UTF-8 still reach rustc intact.
+    let rustflags = target_cpu.rustflags();
+    let mut rustflags_env: Option<(&str, OsString)> = None;
+    if !rustflags.is_empty() {
+        if let Some(mut encoded) = env::var_os("CARGO_ENCODED_RUSTFLAGS") {
+            for flag in &rustflags {
+                if !encoded.is_empty() {
+                    encoded.push("\x1f");
+                }
+                encoded.push(flag);
             }
+            rustflags_env = Some(("CARGO_ENCODED_RUSTFLAGS", encoded));
+        } else if let Some(mut flags) = env::var_os("RUSTFLAGS") {
+            if !flags.is_empty() {
+                flags.push(" ");
+            }
+            flags.push(rustflags.join(" "));
+            rustflags_env = Some(("RUSTFLAGS", flags));
+        } else {
+            // build.rustflags is ignored when there are target rustflags, so carry it over,
+            // unless there already were target rustflags for it to be ignored in favour of
+            let has_target_rustflags = env::var_os(cargo_env_target_cfg(triple, "rustflags"))
+                .is_some()
+                || !cargo_config_values(dir, &["target", triple, "rustflags"]).is_empty();
+            let build_rustflags = match env::var("CARGO_BUILD_RUSTFLAGS") {
+                _ if has_target_rustflags => Vec::new(),
+                Ok(flags) => flags.split_whitespace().map(str::to_string).collect(),
+                Err(_) => config_rustflags(&cargo_config_values(dir, &["build", "rustflags"])),
+            };
+            let rustflags = build_rustflags
+                .into_iter()
+                .chain(rustflags)
+                .collect::<Vec<_>>();
+            cargo_args.insert(
+                arg_insertion_position,
+                format!(
+                    "target.{triple}.rustflags={}",
+                    serde_json::to_string(&rustflags)?
+                )
+                .into(),
+            );
+            cargo_args.insert(arg_insertion_position, "--config".into());
         }
-        _ => {
-            // log::warn!("Parent of current working directory does not exist");
-        }
     }
 
-    cargo_args.insert(arg_insertion_position, triple.into());
-    cargo_args.insert(arg_insertion_position, "--target".into());
+    // Cargo rejects the same `--target` given twice, so don't repeat one the user gave.
+    if !user_targets(user_cargo_args).iter().any(|t| t == triple) {
+        cargo_args.insert(arg_insertion_position, triple.into());
+        cargo_args.insert(arg_insertion_position, "--target".into());
+    }
+
+    // Everything but the output format, for finding the artifacts of a passed through build
+    let passthrough_output = passthrough_output && !json_lines;
+    let artifact_args = passthrough_output.then(|| artifact_pass_args(&cargo_args));
+
+    if let Some(color) = color_arg(user_cargo_args, shell.color_choice()) {
+        cargo_args.insert(arg_insertion_position, color.into());
+        cargo_args.insert(arg_insertion_position, "--color".into());
+    }
 
-    cargo_args.insert(arg_insertion_position, "json-render-diagnostics".into());
-    cargo_args.insert(arg_insertion_position, "--message-format".into());
+    let message_format = message_format(&user_message_format, json_lines, passthrough_output);
+    if !message_format.is_empty() {
+        cargo_args.insert(arg_insertion_position, message_format.into());
+        cargo_args.insert(arg_insertion_position, "--message-format".into());
+    }
 
-    let mut child = cargo_cmd
+    if print_cargo_cmd {
+        println!("# {triple}");
+        println!("cd {:?}", dir);
+        for (k, v) in envs.iter() {
+            println!("{k}={v:?}");
+        }
+        for (k, v) in extra_env.iter() {
+            println!("{k}={v:?}");
+        }
+        if let Some((k, v)) = &rustflags_env {
+            println!("{k}={v:?}");
+        }
+        println!(
+            "{:?} {}",
+            cargo_bin,
+            cargo_args
+                .iter()
+                .map(|arg| format!("{arg:?}"))
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+        println!();
+        return Ok((std::process::ExitStatus::default(), Vec::new()));
+    }
+
+    if let Some((k, v)) = &rustflags_env {
+        cargo_cmd.env(k, v);
+    }
+
+    cargo_cmd
         .args(cargo_args)
         .stdin(Stdio::inherit())
         .stderr(Stdio::inherit())
-        .stdout(Stdio::piped())
-        .spawn()
+        .stdout(if passthrough_output {
+            Stdio::inherit()
+        } else {
+            Stdio::piped()
+        });
+
+    // cargo runs in a process group that is stopped along with cargo-ndk, or when the build
+    // times out, rather than leaving compilers running.
+    let (mut child, group) = Group::spawn(&mut cargo_cmd, deadline.is_some())
         .context("failed spawning cargo process")?;
+    let watchdog = deadline.map(|deadline| Watchdog::new(&group, deadline));
 
-    let reader = BufReader::new(child.stdout.take().context("no stdout available")?);
     let mut artifacts = Vec::new();
+    let mut script_warnings = Vec::new();
 
-    for msg in Message::parse_stream(reader) {
-        match msg? {
+    // cargo's output only comes through here to be rendered when it isn't passed straight through
+    let stdout = child.stdout.take();
+    if stdout.is_none() && !passthrough_output {
+        anyhow::bail!("no stdout available");
+    }
+    for line in stdout
+        .map(BufReader::new)
+        .into_iter()
+        .flat_map(|x| x.lines())
+    {
+        let line = line?;
+        if json_lines {
+            println!("{line}");
+        }
+        match serde_json::from_str(&line).unwrap_or(Message::TextLine(line)) {
             Message::CompilerArtifact(artifact) => artifacts.push(artifact),
-            Message::CompilerMessage(msg) => println!("{msg}"),
-            Message::TextLine(line) => println!("{line}"),
+            Message::CompilerMessage(msg) if !json_lines => println!("{msg}"),
+            Message::TextLine(line) if !json_lines => println!("{line}"),
+            // Build script `cargo:warning=` lines are rendered by cargo itself on stderr, but
+            // what a script linked is otherwise invisible when diagnosing link failures.
+            Message::BuildScriptExecuted(script) => {
+                if verbose_cc {
+                    let package = build_script_package(&script.out_dir);
+                    for warning in build_script_warnings(&script.out_dir) {
+                        shell.warn(format!("{package}: {warning}"))?;
+                        script_warnings.push(format!("{package}: {warning}"));
+                    }
+                }
+                if script.linked_libs.is_empty() {
+                    continue;
+                }
+                shell.very_verbose(|shell| {
+                    let libs = script
+                        .linked_libs
+                        .iter()
+                        .map(|lib| lib.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    shell.status_with_color(
+                        "Linking",
+                        format!("{} from {}", libs, script.package_id.repr),
+                        termcolor::Color::Cyan,
+                    )?;
+                    for path in &script.linked_paths {
+                        shell.status_with_color("Search", path.as_str(), termcolor::Color::Cyan)?;
+                    }
+                    Ok(())
+                })?;
+            }
             _ => {}
         }
     }
 
     let status = child.wait().context("cargo crashed")?;
+    let timed_out = watchdog.is_some_and(|watchdog| watchdog.fired());
+    drop(group);
+    if timed_out {
+        shell.error("the build took longer than --build-timeout and was stopped")?;
+        std::process::exit(TIMEOUT_EXIT_CODE);
+    }
+
+    // Everything is fresh by now, so this only has cargo list what the build produced
+    if let Some(args) = artifact_args.filter(|_| status.success()) {
+        let mut command = Command::new(cargo_bin);
+        command
+            .current_dir(dir)
+            .envs(&envs)
+            .envs(extra_env.iter().map(|(k, v)| (k, v)))
+            .args(args)
+            .args(["--message-format", "json"])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null());
+        if let Some((k, v)) = &rustflags_env {
+            command.env(k, v);
+        }
+        if shell.verbosity() == Verbosity::VeryVerbose {
+            command.env("_CARGO_NDK_VERBOSE", "1");
+        }
+        let output = command
+            .output()
+            .context("failed to run cargo to find the artifacts")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "cargo failed to list the artifacts of the build ({})",
+                output.status
+            );
+        }
+        artifacts.extend(
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .filter_map(|line| match serde_json::from_str(line) {
+                    Ok(Message::CompilerArtifact(artifact)) => Some(artifact),
+                    _ => None,
+                }),
+        );
+    }
+
+    if !status.success() && crate::process::killed_by(&status).is_none() {
+        shell.note("the build used the following NDK configuration:")?;
+        shell.note(format!(
+            "    NDK version: {version} ({})",
+            ndk_home.display()
+        ))?;
+        let host = host_tag(ndk_home);
+        shell.note(format!("    host: {host}"))?;
+        shell.note(format!("    target: {triple} (API level {platform})"))?;
+        shell.note(format!(
+            "    sysroot: {}",
+            ndk_home.join(sysroot_suffix(&host)).display()
+        ))?;
+
+        if !script_warnings.is_empty() {
+            shell.note("the last warnings from build scripts, which may explain the failure:")?;
+            let skip = script_warnings.len().saturating_sub(MAX_REPLAYED_WARNINGS);
+            for warning in &script_warnings[skip..] {
+                shell.note(format!("    {warning}"))?;
+            }
+        }
+    }
 
     Ok((status, artifacts))
 }
 
-pub(crate) fn strip(ndk_home: &Path, bin_path: &Path) -> std::process::ExitStatus {
-    let target_strip = ndk_home.join(ndk_tool(ARCH, "llvm-strip"));
+/// `cargo_manifest` made absolute, with a relative path taken to be relative to `working_dir`,
+/// where cargo-ndk was run, which needn't be where cargo runs.
+pub(crate) fn resolve_manifest(cargo_manifest: &Path, working_dir: &Path) -> PathBuf {
+    let path = working_dir.join(cargo_manifest);
+    dunce::canonicalize(&path).unwrap_or(path)
+}
+
+/// Whether `cargo_manifest`, once resolved, is the one cargo finds by itself when run in `dir`, so
+/// that it needn't be passed with `--manifest-path`.
+fn is_found_in(cargo_manifest: &Path, dir: &Path) -> bool {
+    let dir = dunce::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+    cargo_manifest.parent() == Some(&dir)
+}
+
+/// The arguments to build again with, only to list the artifacts of a build that has finished:
+/// those before any `--`, with `run` only building, and `test` and `bench` not running anything.
+fn artifact_pass_args(cargo_args: &[OsString]) -> Vec<OsString> {
+    let mut args = cargo_args
+        .iter()
+        .take_while(|arg| *arg != "--")
+        .cloned()
+        .collect::<Vec<_>>();
+    match args.first().and_then(|arg| arg.to_str()) {
+        Some("run" | "r") => args[0] = "build".into(),
+        Some("test" | "t" | "bench") if !args.iter().any(|arg| arg == "--no-run") => {
+            args.push("--no-run".into())
+        }
+        _ => {}
+    }
+    args
+}
+
+/// The values of any `--target` options in cargo arguments, up to any `--`.
+pub(crate) fn user_targets(cargo_args: &[String]) -> Vec<String> {
+    let mut targets = Vec::new();
+    let mut iter = cargo_args.iter().take_while(|arg| *arg != "--");
+    while let Some(arg) = iter.next() {
+        if arg == "--target" {
+            targets.extend(iter.next().cloned());
+        } else if let Some(value) = arg.strip_prefix("--target=") {
+            targets.push(value.to_string());
+        }
+    }
+    targets
+}
+
+/// The `--color` to pass on to cargo for the color choice cargo-ndk resolved, e.g. from
+/// `CARGO_TERM_COLOR`, so that the diagnostics cargo renders are colored the same as cargo-ndk's
+/// own messages. A `--color` the user gave cargo is left to cargo.
+fn color_arg(cargo_args: &[String], choice: ColorChoice) -> Option<&'static str> {
+    let given = cargo_args
+        .iter()
+        .take_while(|arg| *arg != "--")
+        .any(|arg| arg == "--color" || arg.starts_with("--color="));
+    match choice {
+        _ if given => None,
+        ColorChoice::Always => Some("always"),
+        ColorChoice::Never => Some("never"),
+        ColorChoice::CargoAuto => None,
+    }
+}
+
+/// The first of the `--target`s given to cargo that conflicts with the `triples` cargo-ndk builds
+/// for. Only the same single triple doesn't, as cargo otherwise builds each one for all of them.
+pub(crate) fn conflicting_target<'a>(
+    user_targets: &'a [String],
+    triples: &[&str],
+) -> Option<&'a str> {
+    user_targets
+        .iter()
+        .find(|user| triples.len() > 1 || triples.iter().any(|triple| triple != user))
+        .map(String::as_str)
+}
+
+/// Remove any `--message-format` options from cargo arguments, up to any `--`, returning the
+/// remaining arguments and the formats that were asked for.
+fn take_message_format(cargo_args: &[String]) -> (Vec<String>, Vec<String>) {
+    let mut args = Vec::with_capacity(cargo_args.len());
+    let mut formats = Vec::new();
+
+    let mut iter = cargo_args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            args.push(arg.clone());
+            args.extend(iter.cloned());
+            break;
+        }
 
-    // log::debug!("strip: {}", &target_strip.display());
+        let value = if arg == "--message-format" {
+            iter.next().cloned()
+        } else if let Some(value) = arg.strip_prefix("--message-format=") {
+            Some(value.to_string())
+        } else {
+            args.push(arg.clone());
+            continue;
+        };
+        formats.extend(
+            value
+                .iter()
+                .flat_map(|value| value.split(','))
+                .map(|format| format.trim().to_string()),
+        );
+    }
 
-    Command::new(target_strip)
+    (args, formats)
+}
+
+/// The `--message-format` to pass to cargo, given the formats the user asked for, or none to
+/// leave cargo's own output alone.
+///
+/// Diagnostics are rendered by cargo unless the raw JSON messages were asked for, with `short`
+/// rendering them compactly.
+fn message_format(user_formats: &[String], json_lines: bool, passthrough_output: bool) -> String {
+    if passthrough_output {
+        user_formats.join(",")
+    } else if json_lines {
+        if user_formats.is_empty() {
+            "json".to_string()
+        } else {
+            user_formats.join(",")
+        }
+    } else if user_formats.iter().any(|format| format == "short") {
+        "json-render-diagnostics,json-diagnostic-short".to_string()
+    } else {
+        "json-render-diagnostics".to_string()
+    }
+}
+
+/// The exit code when `--build-timeout` is exceeded, the same as coreutils' `timeout`.
+const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// The number of build script warnings repeated after a failed build with `--verbose-cc`.
+const MAX_REPLAYED_WARNINGS: usize = 10;
+
+/// The package a build script belongs to, from its `OUT_DIR` of `build/<package>-<hash>/out`.
+fn build_script_package(out_dir: &Utf8Path) -> &str {
+    out_dir
+        .parent()
+        .and_then(|dir| dir.file_name())
+        .map(|name| name.rsplit_once('-').map_or(name, |(name, _hash)| name))
+        .unwrap_or("build script")
+}
+
+/// The `cargo:warning=` lines a build script printed, which cargo hides for dependencies
+/// that aren't local, read from the `output` file next to its `OUT_DIR`.
+fn build_script_warnings(out_dir: &Utf8Path) -> Vec<String> {
+    let Some(output) = out_dir.parent().map(|dir| dir.join("output")) else {
+        return Vec::new();
+    };
+    std::fs::read_to_string(output)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            line.strip_prefix("cargo::warning=")
+                .or_else(|| line.strip_prefix("cargo:warning="))
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+/// The first line of the NDK clang's `--version` output, such as
+/// `Android (10552626, +pgo, +bolt, +lto, +mlgo, based on r487747e) clang version 17.0.2 (...)`.
+pub(crate) fn clang_version(ndk_home: &Path) -> Option<String> {
+    let clang = ndk_home.join(ndk_tool(&host_tag(ndk_home), "clang"));
+    let output = Command::new(clang).arg("--version").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+}
+
+/// The GNU build ID of an ELF file, as a hex string, read with the NDK's `readelf`.
+pub(crate) fn build_id(
+    ndk_home: &Path,
+    triple: &str,
+    legacy_toolchain: bool,
+    path: &Path,
+) -> Option<String> {
+    let readelf = ndk_home.join(ndk_tool(
+        &host_tag(ndk_home),
+        &binutils_tool(triple, "readelf", legacy_toolchain),
+    ));
+
+    let output = Command::new(readelf).arg("-n").arg(path).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Build ID:"))
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// The names of the dynamic symbols a library defines, read with the NDK's `nm`.
+pub(crate) fn exported_symbols(
+    ndk_home: &Path,
+    triple: &str,
+    legacy_toolchain: bool,
+    path: &Path,
+) -> Option<Vec<String>> {
+    let nm = ndk_home.join(ndk_tool(
+        &host_tag(ndk_home),
+        &binutils_tool(triple, "nm", legacy_toolchain),
+    ));
+
+    let output = Command::new(nm)
+        .args(["-D", "--defined-only"])
+        .arg(path)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    // Each line is an address, a type and then the name
+    Some(
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_whitespace().nth(2))
+            .map(str::to_string)
+            .collect(),
+    )
+}
+
+/// Compile a C program for `triple` at the platform level with the NDK's clang.
+pub(crate) fn compile_c(
+    ndk_home: &Path,
+    triple: &str,
+    platform: u8,
+    source: &Path,
+    output: &Path,
+) -> Result<()> {
+    let clang = ndk_home.join(ndk_tool(&host_tag(ndk_home), "clang"));
+    let result = Command::new(&clang)
+        .arg(clang_target(triple, platform))
+        .arg(source)
+        .arg("-o")
+        .arg(output)
+        .output()
+        .with_context(|| format!("failed to run {clang:?}"))?;
+    if !result.status.success() {
+        anyhow::bail!(
+            "clang failed to compile {source:?}: {}",
+            String::from_utf8_lossy(&result.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Run the NDK's `objcopy` for `triple` with `args`.
+fn objcopy(
+    ndk_home: &Path,
+    triple: &str,
+    legacy_toolchain: bool,
+    args: &[&std::ffi::OsStr],
+) -> Result<()> {
+    let target_objcopy = ndk_home.join(ndk_tool(
+        &host_tag(ndk_home),
+        &binutils_tool(triple, "objcopy", legacy_toolchain),
+    ));
+    let status = Command::new(&target_objcopy)
+        .args(args)
+        .status()
+        .with_context(|| format!("failed to run {target_objcopy:?}"))?;
+    if !status.success() {
+        anyhow::bail!("{target_objcopy:?} failed ({status})");
+    }
+    Ok(())
+}
+
+/// Copy the debug info of `bin_path` to `debug_path`, to be kept once `bin_path` is stripped.
+pub(crate) fn only_keep_debug(
+    ndk_home: &Path,
+    triple: &str,
+    legacy_toolchain: bool,
+    bin_path: &Path,
+    debug_path: &Path,
+) -> Result<()> {
+    objcopy(
+        ndk_home,
+        triple,
+        legacy_toolchain,
+        &[
+            "--only-keep-debug".as_ref(),
+            bin_path.as_ref(),
+            debug_path.as_ref(),
+        ],
+    )
+}
+
+/// Point the stripped `bin_path` at its debug info in `debug_path`, with a `.gnu_debuglink`
+/// section naming it, for debuggers and symbolizers to find.
+pub(crate) fn add_gnu_debuglink(
+    ndk_home: &Path,
+    triple: &str,
+    legacy_toolchain: bool,
+    bin_path: &Path,
+    debug_path: &Path,
+) -> Result<()> {
+    let mut link = OsString::from("--add-gnu-debuglink=");
+    link.push(debug_path);
+    objcopy(
+        ndk_home,
+        triple,
+        legacy_toolchain,
+        &[&link, bin_path.as_ref()],
+    )
+}
+
+/// What `--strip` removes from the libraries copied to the output directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum StripMode {
+    /// Only the debug info, keeping the symbol table for readable backtraces
+    Debug,
+    /// Everything that isn't needed to load the library
+    #[default]
+    All,
+}
+
+impl FromStr for StripMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "debug" => StripMode::Debug,
+            "all" => StripMode::All,
+            _ => return Err(format!("Unsupported strip mode: '{s}'")),
+        })
+    }
+}
+
+pub(crate) fn strip(
+    ndk_home: &Path,
+    triple: &str,
+    legacy_toolchain: bool,
+    mode: StripMode,
+    bin_path: &Path,
+) -> Result<()> {
+    let target_strip = ndk_home.join(ndk_tool(
+        &host_tag(ndk_home),
+        &binutils_tool(triple, "strip", legacy_toolchain),
+    ));
+
+    let status = Command::new(&target_strip)
+        .arg(match mode {
+            StripMode::Debug => "--strip-debug",
+            StripMode::All => "--strip-all",
+        })
         .arg(bin_path)
         .status()
-        .expect("strip crashed")
+        .with_context(|| format!("failed to run {target_strip:?}"))?;
+    if !status.success() {
+        anyhow::bail!("{target_strip:?} failed to strip {bin_path:?} ({status})");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of its own for a test to write files in, removed again when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("cargo-ndk-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn host_tag_matches_host_arch() {
+        let prebuilt = names(&["darwin-x86_64", "linux-x86_64", "windows-x86_64"]);
+        assert_eq!(
+            best_host_tag(&prebuilt, "linux", "x86_64").as_deref(),
+            Some("linux-x86_64")
+        );
+        assert_eq!(
+            best_host_tag(&prebuilt, "windows", "x86_64").as_deref(),
+            Some("windows-x86_64")
+        );
+    }
+
+    #[test]
+    fn host_tag_prefers_native_arm_over_emulated_x86_64() {
+        let prebuilt = names(&["linux-aarch64", "linux-x86_64"]);
+        assert_eq!(
+            best_host_tag(&prebuilt, "linux", "aarch64").as_deref(),
+            Some("linux-aarch64")
+        );
+        let prebuilt = names(&["darwin-arm64", "darwin-x86_64"]);
+        assert_eq!(
+            best_host_tag(&prebuilt, "macos", "aarch64").as_deref(),
+            Some("darwin-arm64")
+        );
+    }
+
+    #[test]
+    fn host_tag_falls_back_to_x86_64_on_arm() {
+        // NDKs ship macOS as a universal binary under x86_64, and nothing for Windows on ARM
+        let prebuilt = names(&["darwin-x86_64"]);
+        assert_eq!(
+            best_host_tag(&prebuilt, "macos", "aarch64").as_deref(),
+            Some("darwin-x86_64")
+        );
+        let prebuilt = names(&["windows-x86_64"]);
+        assert_eq!(
+            best_host_tag(&prebuilt, "windows", "aarch64").as_deref(),
+            Some("windows-x86_64")
+        );
+    }
+
+    #[test]
+    fn host_tag_is_none_without_a_match() {
+        assert_eq!(best_host_tag(&[], "linux", "x86_64"), None);
+        assert_eq!(
+            best_host_tag(&names(&["darwin-x86_64"]), "linux", "x86_64"),
+            None
+        );
+        assert_eq!(
+            best_host_tag(&names(&["linux-x86_64"]), "linux", "riscv64"),
+            None
+        );
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn user_targets_are_found_in_both_forms() {
+        assert_eq!(
+            user_targets(&args(&["build", "--target", "aarch64-linux-android"])),
+            args(&["aarch64-linux-android"])
+        );
+        assert_eq!(
+            user_targets(&args(&[
+                "build",
+                "--target=aarch64-linux-android",
+                "--release"
+            ])),
+            args(&["aarch64-linux-android"])
+        );
+        assert!(user_targets(&args(&["build", "--target-dir", "out"])).is_empty());
+        assert!(user_targets(&args(&["build", "--release"])).is_empty());
+    }
+
+    #[test]
+    fn user_targets_after_double_dash_are_ignored() {
+        let cargo_args = args(&["run", "--", "--target", "aarch64-linux-android"]);
+        assert!(user_targets(&cargo_args).is_empty());
+    }
+
+    #[test]
+    fn resolved_color_is_passed_to_cargo() {
+        let cargo_args = args(&["build", "--release"]);
+        assert_eq!(color_arg(&cargo_args, ColorChoice::Always), Some("always"));
+        assert_eq!(color_arg(&cargo_args, ColorChoice::Never), Some("never"));
+        assert_eq!(color_arg(&cargo_args, ColorChoice::CargoAuto), None);
+        for cargo_args in [
+            args(&["build", "--color", "never"]),
+            args(&["build", "--color=never"]),
+        ] {
+            assert_eq!(color_arg(&cargo_args, ColorChoice::Always), None);
+        }
+        let cargo_args = args(&["run", "--", "--color", "never"]);
+        assert_eq!(color_arg(&cargo_args, ColorChoice::Always), Some("always"));
+    }
+
+    #[test]
+    fn matching_user_target_does_not_conflict() {
+        let user = user_targets(&args(&["build", "--target", "aarch64-linux-android"]));
+        assert_eq!(conflicting_target(&user, &["aarch64-linux-android"]), None);
+    }
+
+    #[test]
+    fn mismatching_user_target_conflicts() {
+        let user = user_targets(&args(&["build", "--target=armv7-linux-androideabi"]));
+        assert_eq!(
+            conflicting_target(&user, &["aarch64-linux-android"]),
+            Some("armv7-linux-androideabi")
+        );
+        // Cargo would build each of several targets for the one given to it as well
+        let user = user_targets(&args(&["build", "--target", "aarch64-linux-android"]));
+        assert_eq!(
+            conflicting_target(&user, &["aarch64-linux-android", "armv7-linux-androideabi"]),
+            Some("aarch64-linux-android")
+        );
+    }
+
+    #[test]
+    fn absent_user_target_does_not_conflict() {
+        let user = user_targets(&args(&["build", "--release"]));
+        assert_eq!(
+            conflicting_target(&user, &["aarch64-linux-android", "armv7-linux-androideabi"]),
+            None
+        );
+    }
+
+    fn os_args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn artifact_pass_only_builds() {
+        assert_eq!(
+            artifact_pass_args(&os_args(&["run", "--bin", "foo", "--", "--flag"])),
+            os_args(&["build", "--bin", "foo"])
+        );
+        assert_eq!(
+            artifact_pass_args(&os_args(&["test", "--test", "foo", "--", "filter"])),
+            os_args(&["test", "--test", "foo", "--no-run"])
+        );
+        assert_eq!(
+            artifact_pass_args(&os_args(&["bench"])),
+            os_args(&["bench", "--no-run"])
+        );
+        assert_eq!(
+            artifact_pass_args(&os_args(&["build", "--release"])),
+            os_args(&["build", "--release"])
+        );
+    }
+
+    #[test]
+    fn artifact_pass_does_not_repeat_no_run() {
+        assert_eq!(
+            artifact_pass_args(&os_args(&["test", "--no-run"])),
+            os_args(&["test", "--no-run"])
+        );
+        // One after `--` is for the test binary, not cargo
+        assert_eq!(
+            artifact_pass_args(&os_args(&["t", "--", "--no-run"])),
+            os_args(&["t", "--no-run"])
+        );
+    }
+
+    #[test]
+    fn message_format_is_taken_out_of_cargo_args() {
+        let (rest, formats) = take_message_format(&args(&[
+            "build",
+            "--message-format",
+            "short",
+            "--release",
+            "--message-format=json-diagnostic-short, json-render-diagnostics",
+        ]));
+        assert_eq!(rest, args(&["build", "--release"]));
+        assert_eq!(
+            formats,
+            args(&["short", "json-diagnostic-short", "json-render-diagnostics"])
+        );
+    }
+
+    #[test]
+    fn message_format_after_double_dash_is_left_alone() {
+        let cargo_args = args(&["run", "--", "--message-format", "short"]);
+        let (rest, formats) = take_message_format(&cargo_args);
+        assert_eq!(rest, cargo_args);
+        assert!(formats.is_empty());
+    }
+
+    #[test]
+    fn short_message_format_renders_short_diagnostics() {
+        let (_, formats) = take_message_format(&args(&["build", "--message-format=short"]));
+        assert_eq!(
+            message_format(&formats, false, false),
+            "json-render-diagnostics,json-diagnostic-short"
+        );
+    }
+
+    #[test]
+    fn message_format_defaults_to_rendered_diagnostics() {
+        assert_eq!(message_format(&[], false, false), "json-render-diagnostics");
+        assert_eq!(message_format(&[], true, false), "json");
+        assert_eq!(message_format(&[], false, true), "");
+        assert_eq!(
+            message_format(&args(&["json-diagnostic-short"]), true, false),
+            "json-diagnostic-short"
+        );
+        assert_eq!(message_format(&args(&["short"]), false, true), "short");
+    }
+
+    fn env_for(triple: &str, platform: u8, link_libs: &[String]) -> BTreeMap<String, OsString> {
+        build_env(
+            triple,
+            Path::new("ndk"),
+            platform,
+            false,
+            false,
+            true,
+            None,
+            true,
+            link_libs,
+            &TargetCpu::default(),
+        )
+    }
+
+    #[test]
+    fn bindgen_args_with_spaces_split_into_one_argument_each() {
+        let env = build_env(
+            "aarch64-linux-android",
+            Path::new("/opt/android ndk"),
+            21,
+            true,
+            false,
+            true,
+            None,
+            true,
+            &[],
+            &TargetCpu::default(),
+        );
+        let bindgen_args = env["BINDGEN_EXTRA_CLANG_ARGS_aarch64_linux_android"]
+            .to_str()
+            .unwrap();
+        let bindgen_args = shell_words::split(bindgen_args).unwrap();
+        assert_eq!(bindgen_args.len(), 2, "{bindgen_args:?}");
+        assert!(bindgen_args[0].starts_with("--sysroot=/opt/android ndk/"));
+        assert!(bindgen_args[0].ends_with("/sysroot"));
+        assert!(bindgen_args[1].starts_with("-I/opt/android ndk/"));
+    }
+
+    #[test]
+    fn forward_slashes_keep_invalid_utf8() {
+        assert_eq!(
+            forward_slashes(OsString::from(r"C:\ndk\bin")),
+            OsString::from("C:/ndk/bin")
+        );
+        #[cfg(unix)]
+        {
+            use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+            assert_eq!(
+                forward_slashes(OsStr::from_bytes(b"C:\\nd\xffk\\bin").to_os_string()),
+                OsStr::from_bytes(b"C:/nd\xffk/bin")
+            );
+        }
+    }
+
+    #[test]
+    fn link_libs_are_only_set_when_given() {
+        let env = env_for("aarch64-linux-android", 21, &args(&["log", "android"]));
+        assert_eq!(env["_CARGO_NDK_LINK_LIBS"], "log android");
+        let env = env_for("aarch64-linux-android", 21, &[]);
+        assert!(!env.contains_key("_CARGO_NDK_LINK_LIBS"));
+    }
+
+    #[test]
+    fn config_rustflags_are_joined_least_specific_first() {
+        let values = [
+            toml::Value::Array(vec!["-Cforce-frame-pointers=yes".into()]),
+            toml::Value::String("--cfg home  -Cdebuginfo=1".into()),
+        ];
+        assert_eq!(
+            config_rustflags(&values),
+            args(&[
+                "--cfg",
+                "home",
+                "-Cdebuginfo=1",
+                "-Cforce-frame-pointers=yes"
+            ])
+        );
+        assert!(config_rustflags(&[]).is_empty());
+    }
+
+    #[test]
+    fn build_rustflags_are_read_from_config() {
+        let dir = TempDir::new("build-rustflags");
+        std::fs::create_dir_all(dir.0.join(".cargo")).unwrap();
+        std::fs::write(
+            dir.0.join(".cargo").join("config.toml"),
+            "[build]\nrustflags = [\"--cfg\", \"from_config\"]\n",
+        )
+        .unwrap();
+
+        let values = cargo_config_values(&dir.0, &["build", "rustflags"]);
+        assert_eq!(
+            values[0],
+            toml::Value::Array(vec!["--cfg".into(), "from_config".into()])
+        );
+    }
+
+    #[test]
+    fn relative_manifest_from_unrelated_dir_is_passed_to_cargo() {
+        let root = TempDir::new("manifest-path");
+        let project = root.0.join("project");
+        let unrelated = root.0.join("unrelated");
+        std::fs::create_dir_all(&project).unwrap();
+        std::fs::create_dir_all(&unrelated).unwrap();
+        std::fs::write(project.join("Cargo.toml"), "").unwrap();
+
+        let manifest = resolve_manifest(Path::new("../project/Cargo.toml"), &unrelated);
+        assert!(manifest.is_absolute());
+        assert_eq!(
+            manifest,
+            dunce::canonicalize(project.join("Cargo.toml")).unwrap()
+        );
+        assert!(!is_found_in(&manifest, &unrelated));
+        // Even when it's relative to an unrelated dir, it's found if cargo runs beside it
+        assert!(is_found_in(
+            &manifest,
+            &unrelated.join("..").join("project")
+        ));
+    }
+
+    #[test]
+    fn manifest_in_working_dir_is_not_passed_to_cargo() {
+        let root = TempDir::new("manifest-in-dir");
+        std::fs::write(root.0.join("Cargo.toml"), "").unwrap();
+
+        let manifest = resolve_manifest(Path::new("Cargo.toml"), &root.0);
+        assert!(is_found_in(&manifest, &root.0));
+        assert!(!is_found_in(&manifest, &root.0.join("..")));
+    }
+
+    #[test]
+    fn clang_target_platform_is_the_trailing_level() {
+        assert_eq!(
+            clang_target_platform("--target=aarch64-linux-android21"),
+            Some(21)
+        );
+        // The last one wins, as it does for clang
+        assert_eq!(
+            clang_target_platform(
+                "--target=aarch64-linux-android21 -O2 --target=aarch64-linux-android30"
+            ),
+            Some(30)
+        );
+        assert_eq!(
+            clang_target_platform("--target=aarch64-linux-android"),
+            None
+        );
+        assert_eq!(clang_target_platform("-O2"), None);
+    }
+
+    #[test]
+    fn cmake_and_cc_get_the_same_platform() {
+        for (triple, platform) in [
+            ("aarch64-linux-android", 21),
+            ("armv7-linux-androideabi", 30),
+        ] {
+            let env = env_for(triple, platform, &[]);
+            let level = platform.to_string();
+            assert_eq!(env["ANDROID_PLATFORM"], *level);
+            assert_eq!(env["ANDROID_NATIVE_API_LEVEL"], *level);
+            for base in ["CFLAGS", "CXXFLAGS"] {
+                let flags = env[&format!("{base}_{triple}")].to_string_lossy();
+                assert_eq!(clang_target_platform(&flags), Some(platform), "{flags}");
+            }
+        }
+    }
+
+    #[test]
+    fn android_platform_is_the_numeric_level() {
+        let env = env_for("aarch64-linux-android", 26, &[]);
+        assert_eq!(env["CARGO_NDK_ANDROID_PLATFORM"], "26");
+        assert_eq!(env["CARGO_NDK_ANDROID_TARGET"], "arm64-v8a");
+        assert_eq!(env["ANDROID_ABI"], "arm64-v8a");
+
+        let env = env_for("i686-linux-android", 21, &[]);
+        assert_eq!(env["CARGO_NDK_ANDROID_PLATFORM"], "21");
+        assert_eq!(env["CARGO_NDK_ANDROID_TARGET"], "x86");
+    }
+
+    // Each test uses variables of its own, as tests run at the same time in the one environment
+
+    #[test]
+    fn bare_cc_var_is_set_for_the_target_only() {
+        env::set_var("CARGO_NDK_TEST_BARE_CC", "host-cc");
+        assert_eq!(
+            cc_env("CARGO_NDK_TEST_BARE_CC", "aarch64-linux-android"),
+            (
+                "CARGO_NDK_TEST_BARE_CC_aarch64-linux-android".to_string(),
+                Some("host-cc".to_string())
+            )
+        );
+    }
+
+    #[test]
+    fn target_cc_var_keeps_its_key() {
+        env::set_var(
+            "CARGO_NDK_TEST_TARGET_CC_aarch64_linux_android",
+            "android-cc",
+        );
+        env::set_var("CARGO_NDK_TEST_TARGET_CC", "host-cc");
+        assert_eq!(
+            cc_env("CARGO_NDK_TEST_TARGET_CC", "aarch64-linux-android"),
+            (
+                "CARGO_NDK_TEST_TARGET_CC_aarch64_linux_android".to_string(),
+                Some("android-cc".to_string())
+            )
+        );
+    }
+
+    #[test]
+    fn unset_cc_var_uses_the_triple_key() {
+        assert_eq!(
+            cc_env("CARGO_NDK_TEST_UNSET_CC", "x86_64-linux-android"),
+            (
+                "CARGO_NDK_TEST_UNSET_CC_x86_64-linux-android".to_string(),
+                None
+            )
+        );
+    }
+
+    #[test]
+    fn build_env_has_no_bare_compiler_vars() {
+        // Host build scripts would use these too, as the `cc` crate falls back to them
+        let env = env_for("aarch64-linux-android", 21, &[]);
+        for bare in ["CC", "CXX", "AR", "RANLIB", "CFLAGS", "CXXFLAGS"] {
+            assert!(!env.contains_key(bare), "{bare} is set");
+            assert!(
+                !env.contains_key(&format!("HOST_{bare}")),
+                "HOST_{bare} is set"
+            );
+            assert!(env.contains_key(&format!("{bare}_aarch64-linux-android")));
+        }
+    }
+
+    #[test]
+    fn cfg_matches_android_targets() {
+        let arm64 = "aarch64-linux-android";
+        let armv7 = "armv7-linux-androideabi";
+        assert!(cfg_matches(r#"cfg(target_os = "android")"#, arm64));
+        assert!(cfg_matches("cfg(unix)", armv7));
+        assert!(cfg_matches(
+            r#"cfg(all(target_arch = "aarch64", target_os = "android"))"#,
+            arm64
+        ));
+        assert!(!cfg_matches(
+            r#"cfg(all(target_arch = "aarch64", target_os = "android"))"#,
+            armv7
+        ));
+        assert!(cfg_matches(
+            r#"cfg(any(windows, target_pointer_width = "32"))"#,
+            armv7
+        ));
+        assert!(cfg_matches(r#"cfg(not(target_os = "linux"))"#, arm64));
+        assert!(!cfg_matches("cfg(windows)", arm64));
+        assert!(!cfg_matches("cfg(not(unix))", arm64));
+        // Not a cfg at all, or not one that can be understood
+        assert!(!cfg_matches(arm64, arm64));
+        assert!(!cfg_matches("cfg(all(unix)", arm64));
+        assert!(!cfg_matches("cfg(unix unix)", arm64));
+    }
+
+    #[test]
+    fn runner_under_matching_cfg_is_kept() {
+        let dir = TempDir::new("cfg-runner");
+        std::fs::create_dir_all(dir.0.join(".cargo")).unwrap();
+        let config = dir.0.join(".cargo").join("config.toml");
+
+        std::fs::write(
+            &config,
+            "[target.'cfg(all(target_os = \"android\", target_arch = \"x86_64\"))']\nrunner = \"mine\"\n",
+        )
+        .unwrap();
+        assert!(has_configured_runner("x86_64-linux-android", &dir.0));
+        assert!(!has_configured_runner("i686-linux-android", &dir.0));
+
+        std::fs::write(&config, "[target.'cfg(windows)']\nrunner = \"mine\"\n").unwrap();
+        assert!(!has_configured_runner("x86_64-linux-android", &dir.0));
+
+        std::fs::write(
+            &config,
+            "[target.x86_64-linux-android]\nrunner = \"mine\"\n",
+        )
+        .unwrap();
+        assert!(has_configured_runner("x86_64-linux-android", &dir.0));
+    }
+
+    #[test]
+    fn linker_in_config_is_found() {
+        let dir = TempDir::new("config-linker");
+        std::fs::create_dir_all(dir.0.join(".cargo")).unwrap();
+        let config = dir.0.join(".cargo").join("config.toml");
+
+        assert_eq!(configured_linker("x86_64-linux-android", &dir.0), None);
+
+        std::fs::write(
+            &config,
+            "[target.x86_64-linux-android]\nlinker = \"mine\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            configured_linker("x86_64-linux-android", &dir.0).as_deref(),
+            Some("target.x86_64-linux-android.linker = \"mine\"")
+        );
+        assert_eq!(configured_linker("i686-linux-android", &dir.0), None);
+    }
+
+    #[test]
+    fn only_commands_that_run_binaries_get_a_runner() {
+        assert!(runs_binaries(&args(&["test", "--lib"])));
+        assert!(runs_binaries(&args(&["r"])));
+        assert!(runs_binaries(&args(&["bench"])));
+        assert!(!runs_binaries(&args(&["build"])));
+        assert!(!runs_binaries(&[]));
+    }
+
+    #[test]
+    fn no_run_only_builds_tests() {
+        assert!(only_builds_tests(&args(&["test", "--no-run"])));
+        assert!(only_builds_tests(&args(&["bench", "--lib", "--no-run"])));
+        assert!(!only_builds_tests(&args(&["test"])));
+        assert!(!only_builds_tests(&args(&["test", "--", "--no-run"])));
+        assert!(!only_builds_tests(&args(&["build", "--no-run"])));
+    }
+
+    #[test]
+    fn config_values_are_found_in_parent_dirs_most_specific_first() {
+        let root = TempDir::new("config-values");
+        let project = root.0.join("project");
+        std::fs::create_dir_all(project.join(".cargo")).unwrap();
+        std::fs::create_dir_all(root.0.join(".cargo")).unwrap();
+        std::fs::write(
+            root.0.join(".cargo").join("config.toml"),
+            "[target.x86_64-linux-android]\nrunner = \"outer\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            project.join(".cargo").join("config.toml"),
+            "[target.x86_64-linux-android]\nrunner = \"inner\"\n",
+        )
+        .unwrap();
+
+        let key = ["target", "x86_64-linux-android", "runner"];
+        let values = cargo_config_values(&project.join("src"), &key);
+        assert_eq!(values[..2], ["inner".into(), "outer".into()]);
+        assert!(
+            cargo_config_values(&project, &["target", "i686-linux-android", "runner"]).is_empty()
+        );
+    }
 }
@@ -4,10 +4,11 @@ use std::{
     ffi::OsString,
     fmt::Display,
     fs,
-    io::{self, ErrorKind},
-    path::{Path, PathBuf},
-    time::Instant,
+    io::{self, ErrorKind, Write},
     panic,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
 };
 
 // Can be removed when MSRV is bumped to 1.81+.
@@ -15,14 +16,26 @@ use std::{
 pub type PanicHookInfo<'a> = std::panic::PanicInfo<'a>;
 
 use anyhow::Context;
-use cargo_metadata::{camino::Utf8Path, semver::Version, Artifact, MetadataCommand};
+use cargo_metadata::{
+    camino::Utf8Path, semver::Version, Artifact, Metadata, MetadataCommand, Package,
+};
 use filetime::FileTime;
 use gumdrop::Options;
+use serde::Serialize;
 
 use crate::{
-    cargo::{build_env, clang_target},
+    cargo::{build_env, cc_env, StripMode, TargetCpu},
+    compress::Compression,
+    env_vars::{OUTPUT_DIR_ENV, OUT_FORMAT_ENV, STRIP_ENV},
+    lock::{NdkLock, LOCKFILE_NAME},
     meta::{Ndk, Target},
+    output_lock::OutputLock,
+    runner::RunResult,
     shell::{Shell, Verbosity},
+    size::{LibrarySize, SizeManifest},
+    source_hashes::SourceHashes,
+    symbols::{SymbolFile, SymbolIndex},
+    timings::{TargetTiming, TimingsReport},
 };
 
 #[derive(Debug, Options)]
@@ -44,15 +57,56 @@ struct ArgsEnv {
     bindgen: bool,
 
     #[options(
-        help = "triples for the target. Additionally, Android target names are supported: armeabi-v7a arm64-v8a x86 x86_64"
+        help = "triples for the target. Additionally, Android target names are supported: armeabi-v7a arm64-v8a x86 x86_64 riscv64"
     )]
     target: Target,
 
+    #[options(
+        no_short,
+        help = "use the pre-r23 NDK toolchain layout (GNU binutils names) for NDK r19 to r22",
+        default = "false"
+    )]
+    legacy_toolchain: bool,
+
+    #[options(
+        no_short,
+        help = "don't set the PKG_CONFIG_* environment variables for cross compiling",
+        default = "false"
+    )]
+    no_pkg_config_setup: bool,
+
+    #[options(
+        no_short,
+        help = "don't set cargo-ndk up as the linker, leaving the one you've configured",
+        default = "false"
+    )]
+    no_linker_override: bool,
+
+    #[options(
+        no_short,
+        meta = "DIR",
+        help = "prebuilt OpenSSL for openssl-sys to use, either one install or one per ABI in DIR/<abi>"
+    )]
+    openssl_sysroot: Option<PathBuf>,
+
     #[options(no_short, help = "use PowerShell syntax")]
     powershell: bool,
 
     #[options(no_short, help = "print output in JSON format")]
     json: bool,
+
+    #[options(
+        no_short,
+        meta = "FORMAT",
+        help = "output format: bash, powershell, json, or null (NUL-delimited KEY=VALUE pairs)"
+    )]
+    format: Option<EnvFormat>,
+
+    #[options(
+        no_short,
+        help = "include cargo-ndk's internal variables (_CARGO_NDK_*) in JSON output; these are unstable"
+    )]
+    include_internal: bool,
 }
 
 #[derive(Debug, Options)]
@@ -68,748 +122,3303 @@ struct Args {
 
     #[options(
         meta = "DIR",
-        help = "output to a jniLibs directory in the correct sub-directories"
+        help = "output to a jniLibs directory in the correct sub-directories (unlike cargo's --artifact-dir, which copies without the per-ABI layout); overrides CARGO_NDK_OUTPUT_DIR and output_dir in [package.metadata.ndk]"
     )]
     output_dir: Option<PathBuf>,
 
-    #[options(help = "platform (also known as API level)")]
+    #[options(
+        no_short,
+        meta = "BASE",
+        help = "what a relative --output-dir is relative to: cwd (default), manifest, or workspace-root"
+    )]
+    output_dir_base: Option<OutputDirBase>,
+
+    #[options(
+        help = "platform (also known as API level); overrides platform in [package.metadata.ndk], or 21 without it"
+    )]
     platform: Option<u8>,
 
     #[options(no_short, help = "disable stripping debug symbols", default = "false")]
     no_strip: bool,
 
+    #[options(
+        no_short,
+        meta = "MODE",
+        help = "what to strip from the libraries copied to the output dir: all (default) or debug, which keeps the symbol table"
+    )]
+    strip: Option<StripMode>,
+
+    #[options(
+        no_short,
+        help = "build the smallest library: opt-level \"z\", LTO, one codegen unit and panic = \"abort\""
+    )]
+    optimize_size: bool,
+
+    #[options(
+        no_short,
+        help = "copy libraries to the output directory only if their content changed, rather than going by modification times"
+    )]
+    copy_if_changed: bool,
+
+    #[options(
+        no_short,
+        help = "warn instead of failing if the output directory cannot be created",
+        default = "false"
+    )]
+    allow_missing_output: bool,
+
+    #[options(
+        no_short,
+        help = "warn instead of failing when a target produces no cdylib to copy to the output directory",
+        default = "false"
+    )]
+    ignore_no_cdylib: bool,
+
     #[options(no_short, meta = "PATH", help = "path to Cargo.toml")]
     manifest_path: Option<PathBuf>,
 
     #[options(
         no_short,
-        help = "set bindgen-specific environment variables (BINDGEN_EXTRA_CLANG_ARGS_*) when building",
+        meta = "PATH",
+        help = "cargo binary to build with, instead of the one running cargo-ndk"
+    )]
+    cargo: Option<PathBuf>,
+
+    #[options(
+        no_short,
+        help = "don't set the PKG_CONFIG_* environment variables for cross compiling",
         default = "false"
     )]
-    bindgen: bool,
+    no_pkg_config_setup: bool,
 
     #[options(
-        help = "triples for the target(s). Additionally, Android target names are supported: armeabi-v7a arm64-v8a x86 x86_64"
+        no_short,
+        help = "don't set cargo-ndk up as the linker, leaving the one you've configured",
+        default = "false"
     )]
-    target: Vec<Target>,
-}
+    no_linker_override: bool,
 
-fn highest_version_ndk_in_path(ndk_dir: &Path) -> Option<PathBuf> {
-    if ndk_dir.exists() {
-        fs::read_dir(ndk_dir)
-            .ok()?
-            .filter_map(Result::ok)
-            .filter_map(|x| {
-                let path = x.path();
-                path.components()
-                    .last()
-                    .and_then(|comp| comp.as_os_str().to_str())
-                    .and_then(|name| Version::parse(name).ok())
-                    .map(|version| (version, path))
-            })
-            .max_by(|(a, _), (b, _)| a.cmp(b))
-            .map(|(_, path)| path)
-    } else {
-        None
-    }
-}
+    #[options(
+        no_short,
+        meta = "NAME",
+        help = "link against a library, such as log or android, as with -lNAME (can be repeated)"
+    )]
+    link_lib: Vec<String>,
 
-/// Return the name and value of the first environment variable that is set
-///
-/// Additionally checks that if any other variables are set then they should
-/// be consistent with the first variable, otherwise a warning is printed.
-fn find_first_consistent_var_set<'a>(
-    vars: &'a [&str],
-    shell: &mut Shell,
-) -> Option<(&'a str, OsString)> {
-    let mut first_var_set = None;
-    for var in vars {
-        if let Some(path) = env::var_os(var) {
-            if let Some((first_var, first_path)) = first_var_set.as_ref() {
-                if *first_path != path {
-                    shell
-                        .warn(format!(
-                            "Environment variable `{} = {:#?}` doesn't match `{} = {:#?}`",
-                            first_var, first_path, var, path
-                        ))
-                        .unwrap();
-                }
-                continue;
-            }
-            first_var_set = Some((*var, path));
-        }
-    }
+    #[options(
+        no_short,
+        meta = "CPU",
+        help = "generate code for a specific CPU, such as cortex-a76, in both Rust and C code"
+    )]
+    target_cpu: Option<String>,
 
-    first_var_set
-}
+    #[options(
+        no_short,
+        meta = "FEATURE",
+        help = "enable a CPU feature, such as +dotprod, in both Rust and C code (can be repeated)"
+    )]
+    target_feature: Vec<String>,
 
-/// Return a path to a discovered NDK and string describing how it was found
-fn derive_ndk_path(shell: &mut Shell) -> Option<(PathBuf, String)> {
-    let ndk_vars = [
-        "ANDROID_NDK_HOME",
-        "ANDROID_NDK_ROOT",
-        "ANDROID_NDK_PATH",
-        "NDK_HOME",
-    ];
-    if let Some((var_name, path)) = find_first_consistent_var_set(&ndk_vars, shell) {
-        let path = PathBuf::from(path);
-        return highest_version_ndk_in_path(&path)
-            .or(Some(path))
-            .map(|path| (path, var_name.to_string()));
-    }
+    #[options(
+        no_short,
+        help = "fail if the NDK, clang, platform or targets differ from cargo-ndk.lock (see `cargo ndk lock`)"
+    )]
+    locked: bool,
 
-    let sdk_vars = ["ANDROID_HOME", "ANDROID_SDK_ROOT", "ANDROID_SDK_HOME"];
-    if let Some((var_name, sdk_path)) = find_first_consistent_var_set(&sdk_vars, shell) {
-        let ndk_path = PathBuf::from(&sdk_path).join("ndk");
-        if let Some(v) = highest_version_ndk_in_path(&ndk_path) {
-            return Some((v, var_name.to_string()));
-        }
-    }
+    #[options(
+        no_short,
+        meta = "DIR",
+        help = "prebuilt OpenSSL for openssl-sys to use, either one install or one per ABI in DIR/<abi>"
+    )]
+    openssl_sysroot: Option<PathBuf>,
 
-    let ndk_dir = default_ndk_dir();
-    highest_version_ndk_in_path(&ndk_dir).map(|path| (path, "standard location".to_string()))
-}
+    #[options(
+        no_short,
+        meta = "junit=PATH",
+        help = "write a report of the tests run on the device; only JUnit XML is supported"
+    )]
+    test_report: Option<String>,
 
-fn print_usage() {
-    println!("cargo-ndk <https://github.com/bbqsrc/cargo-ndk>\n\nUsage: cargo ndk [OPTIONS] <CARGO_ARGS>\n");
-    println!("{}", Args::usage());
-}
+    #[options(
+        no_short,
+        help = "build the binaries cargo would run on the device, then show which device, directory and commands would be used, without pushing or running them"
+    )]
+    dry_run: bool,
 
-fn print_usage_env() {
-    println!(
-        "cargo-ndk-env <https://github.com/bbqsrc/cargo-ndk>\n\nUsage: cargo ndk-env [OPTIONS]\n"
-    );
-    println!("{}", ArgsEnv::usage());
-}
+    #[options(
+        no_short,
+        meta = "SERIAL",
+        help = "the device to push to and run on, overriding ANDROID_SERIAL, when more than one is connected"
+    )]
+    adb_serial: Option<String>,
 
-fn default_ndk_dir() -> PathBuf {
-    #[cfg(windows)]
-    let dir = pathos::user::local_dir()
-        .unwrap()
-        .to_path_buf()
-        .join("Android")
-        .join("sdk")
-        .join("ndk");
+    #[options(
+        no_short,
+        help = "hide adb's own output, such as transfer stats, when running on a device (implied by -q)"
+    )]
+    quiet_adb: bool,
 
-    #[cfg(target_os = "linux")]
-    let dir = pathos::xdg::home_dir()
-        .unwrap()
-        .join("Android")
-        .join("Sdk")
-        .join("ndk");
+    #[options(
+        no_short,
+        meta = "PACKAGE",
+        help = "run binaries on the device as a debuggable app, in its data dir and context, using run-as"
+    )]
+    run_as: Option<String>,
 
-    #[cfg(target_os = "macos")]
-    let dir = pathos::user::home_dir()
-        .unwrap()
-        .join("Library")
-        .join("Android")
-        .join("sdk")
-        .join("ndk");
+    #[options(
+        no_short,
+        meta = "CMD",
+        help = "run binaries on the device under a command, such as `taskset f0` or a profiler"
+    )]
+    device_shell_prefix: Option<String>,
 
-    #[cfg(not(any(target_os = "macos", target_os = "linux", target_os = "windows")))]
-    let dir = PathBuf::new();
+    #[options(
+        no_short,
+        meta = "DIR",
+        help = "push binaries to DIR on the device and run them from there (default: /data/local/tmp/cargo-ndk)"
+    )]
+    device_dir: Option<String>,
 
-    dir
-}
+    #[options(
+        no_short,
+        meta = "CLASS",
+        help = "run binaries on the device by starting the Java class CLASS with app_process, passing it the binary's path and arguments"
+    )]
+    via_app_process: Option<String>,
 
-fn derive_ndk_version(path: &Path) -> anyhow::Result<Version> {
-    let data = fs::read_to_string(path.join("source.properties"))?;
-    for line in data.split('\n') {
-        if line.starts_with("Pkg.Revision") {
-            let mut chunks = line.split(" = ");
-            let _ = chunks
-                .next()
-                .ok_or_else(|| io::Error::new(ErrorKind::Other, "No chunk"))?;
-            let version = chunks
-                .next()
-                .ok_or_else(|| io::Error::new(ErrorKind::Other, "No chunk"))?;
-            let version = match Version::parse(version) {
-                Ok(v) => v,
-                Err(_e) => {
-                    return Err(anyhow::anyhow!(format!(
-                        "Could not parse NDK version. Got: '{}'",
-                        version
-                    )));
-                }
-            };
-            return Ok(version);
-        }
-    }
+    #[options(
+        no_short,
+        meta = "JAR",
+        help = "a jar or APK with dex code to push and put on the classpath for --via-app-process"
+    )]
+    app_process_classpath: Option<PathBuf>,
 
-    Err(anyhow::anyhow!("Could not find Pkg.Revision in given path"))
-}
+    #[options(
+        no_short,
+        meta = "SECS",
+        help = "stop the build if building all of the targets takes longer than this"
+    )]
+    build_timeout: Option<u64>,
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub(crate) enum BuildMode {
-    Debug,
-    Release,
-    Profile(String),
-}
+    #[options(
+        no_short,
+        meta = "KEY=VALUE",
+        help = "set an environment variable for the cargo invocation (can be repeated)"
+    )]
+    env: Vec<String>,
 
-impl Display for BuildMode {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(match self {
-            BuildMode::Debug => "debug",
-            BuildMode::Release => "release",
-            BuildMode::Profile(x) => x,
-        })
-    }
+    #[options(
+        no_short,
+        meta = "PATH",
+        help = "set the environment variables in a JSON file from `cargo ndk-env --json`, for debugging"
+    )]
+    env_file: Option<PathBuf>,
+
+    #[options(
+        no_short,
+        help = "attempt to build with an NDK older than r23, which is not supported",
+        default = "false"
+    )]
+    allow_old_ndk: bool,
+
+    #[options(
+        no_short,
+        help = "don't detect the NDK version, and assume it is supported",
+        default = "false"
+    )]
+    skip_ndk_version_check: bool,
+
+    #[options(
+        no_short,
+        help = "don't check that the Rust standard library is installed for each target before building",
+        default = "false"
+    )]
+    skip_target_check: bool,
+
+    #[options(
+        no_short,
+        help = "install the Rust standard library for any target that lacks it with rustup, rather than failing",
+        default = "false"
+    )]
+    install_targets: bool,
+
+    #[options(
+        no_short,
+        meta = "TRIPLE",
+        help = "build build scripts and proc-macros for this host, with the rustup toolchain for it (<channel>-<host>, which needs the Android targets installed too)"
+    )]
+    host_target: Option<String>,
+
+    #[options(
+        no_short,
+        help = "check that the NDK has a sysroot for each target at the platform level before building",
+        default = "false"
+    )]
+    check_ndk_abi_support: bool,
+
+    #[options(
+        no_short,
+        meta = "TARGET=PATH",
+        help = "build TARGET with the NDK at PATH rather than the detected one (repeatable)"
+    )]
+    ndk_home: Vec<String>,
+
+    #[options(
+        no_short,
+        help = "use the pre-r23 NDK toolchain layout (GNU binutils names) for NDK r19 to r22",
+        default = "false"
+    )]
+    legacy_toolchain: bool,
+
+    #[options(
+        no_short,
+        meta = "PATH",
+        help = "write the sizes of the copied libraries to a JSON file"
+    )]
+    size_manifest: Option<PathBuf>,
+
+    #[options(
+        no_short,
+        meta = "PATH",
+        help = "write how long each target took to build, with the number and size of its libraries, to a JSON file"
+    )]
+    timings_json: Option<PathBuf>,
+
+    #[options(
+        no_short,
+        meta = "PATH",
+        help = "compare the sizes of the copied libraries against a previous --size-manifest"
+    )]
+    size_baseline: Option<PathBuf>,
+
+    #[options(
+        no_short,
+        meta = "KINDS",
+        help = "comma-separated kinds of artifact to copy to the output dir: cdylib (default), bin, example"
+    )]
+    collect: Vec<String>,
+
+    #[options(
+        no_short,
+        help = "push each binary and example copied with --collect to the device, in /data/local/tmp/cargo-ndk/<abi>"
+    )]
+    push_all: bool,
+
+    #[options(
+        no_short,
+        help = "load each library built on the connected device with dlopen, to catch unresolved symbols and missing dependencies"
+    )]
+    smoke_test: bool,
+
+    #[options(
+        no_short,
+        help = "lock each ABI's output directory while copying, for invocations sharing an output directory"
+    )]
+    output_lock: bool,
+
+    #[options(
+        no_short,
+        meta = "FORMAT",
+        help = "also write a compressed copy of each copied library beside it, as zstd (.so.zst) or gzip (.so.gz)"
+    )]
+    compress: Option<Compression>,
+
+    #[options(
+        no_short,
+        meta = "DIR",
+        help = "keep an unstripped copy of each copied library for crash reporting, in DIR/<abi>/<build-id>"
+    )]
+    symbols_dir: Option<PathBuf>,
+
+    #[options(
+        no_short,
+        help = "move the debug info of each copied library to <name>.debug beside it before stripping, linked with .gnu_debuglink"
+    )]
+    split_debug_info: bool,
+
+    #[options(
+        no_short,
+        meta = "DIR",
+        help = "put the debug info from --split-debug-info in DIR/<abi> instead of the output dir"
+    )]
+    debug_dir: Option<PathBuf>,
+
+    #[options(
+        no_short,
+        help = "warn if a built library exports no JNI symbols (JNI_OnLoad or Java_...)"
+    )]
+    check_jni: bool,
+
+    #[options(
+        no_short,
+        meta = "CLASS",
+        help = "warn if a built library exports no native methods for CLASS, e.g. com.example.Native (implies --check-jni)"
+    )]
+    jni_class: Vec<String>,
+
+    #[options(
+        no_short,
+        meta = "PERCENT",
+        help = "fail if any library grew by more than this percentage over --size-baseline"
+    )]
+    size_threshold: Option<f64>,
+
+    #[options(
+        no_short,
+        help = "print the C compiler environment and a sample clang command line for each target, then exit"
+    )]
+    dump_clang_cmdline: bool,
+
+    #[options(
+        no_short,
+        help = "list the libraries the NDK's sysroot provides for each target at the platform level, then exit"
+    )]
+    print_sysroot_libs: bool,
+
+    #[options(
+        no_short,
+        help = "print only the environment variables cargo-ndk would add or change for each target, then exit"
+    )]
+    print_env_diff: bool,
+
+    #[options(
+        no_short,
+        help = "print --print-sysroot-libs, --print-env-diff or --list-env-vars output in JSON format"
+    )]
+    json: bool,
+
+    #[options(
+        no_short,
+        help = "list the environment variables cargo-ndk reads, with their flags, and the ones it sets for the build"
+    )]
+    list_env_vars: bool,
+
+    #[options(
+        no_short,
+        help = "let cargo's own output through to the terminal untouched, finding the artifacts with a second cargo pass"
+    )]
+    passthrough_output: bool,
+
+    #[options(
+        no_short,
+        help = "print the paths of the built libraries as JSON keyed by ABI to stdout, with or without --output-dir"
+    )]
+    print_artifacts: bool,
+
+    #[options(
+        no_short,
+        help = "print the cargo command and environment that would be run for each target, without running it"
+    )]
+    print_cargo_cmd: bool,
+
+    #[options(
+        no_short,
+        help = "print cargo's JSON messages to stdout as they are, rather than rendering them"
+    )]
+    stdout_json_lines: bool,
+
+    #[options(
+        no_short,
+        help = "show all build script warnings, and repeat the last of them if the build fails"
+    )]
+    verbose_cc: bool,
+
+    #[options(
+        no_short,
+        help = "set bindgen-specific environment variables (BINDGEN_EXTRA_CLANG_ARGS_*) when building",
+        default = "false"
+    )]
+    bindgen: bool,
+
+    #[options(
+        help = "triples for the target(s). Additionally, Android target names are supported: armeabi-v7a arm64-v8a x86 x86_64 riscv64. Add :LEVEL to build one at its own platform level, e.g. arm64-v8a:30. Overrides targets in [package.metadata.ndk]"
+    )]
+    target: Vec<TargetArg>,
+}
+
+fn highest_version_ndk_in_path(ndk_dir: &Path) -> Option<PathBuf> {
+    if ndk_dir.exists() {
+        fs::read_dir(ndk_dir)
+            .ok()?
+            .filter_map(Result::ok)
+            .filter_map(|x| {
+                let path = x.path();
+                path.components()
+                    .next_back()
+                    .and_then(|comp| comp.as_os_str().to_str())
+                    .and_then(|name| Version::parse(name).ok())
+                    .map(|version| (version, path))
+            })
+            .max_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(_, path)| path)
+    } else {
+        None
+    }
+}
+
+/// Return the name and value of the first environment variable that is set
+///
+/// Additionally checks that if any other variables are set then they should
+/// be consistent with the first variable, otherwise a warning is printed.
+fn find_first_consistent_var_set<'a>(
+    vars: &'a [&str],
+    shell: &mut Shell,
+) -> Option<(&'a str, OsString)> {
+    let mut first_var_set = None;
+    for var in vars {
+        if let Some(path) = env::var_os(var) {
+            if let Some((first_var, first_path)) = first_var_set.as_ref() {
+                if *first_path != path {
+                    shell
+                        .warn(format!(
+                            "Environment variable `{} = {:#?}` doesn't match `{} = {:#?}`",
+                            first_var, first_path, var, path
+                        ))
+                        .unwrap();
+                }
+                continue;
+            }
+            first_var_set = Some((*var, path));
+        }
+    }
+
+    first_var_set
+}
+
+/// Return a path to a discovered NDK and string describing how it was found
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, ret)
+)]
+fn derive_ndk_path(shell: &mut Shell) -> Option<(PathBuf, String)> {
+    let ndk_vars = [
+        "ANDROID_NDK_HOME",
+        "ANDROID_NDK_ROOT",
+        "ANDROID_NDK_PATH",
+        "NDK_HOME",
+    ];
+    if let Some((var_name, path)) = find_first_consistent_var_set(&ndk_vars, shell) {
+        let path = PathBuf::from(path);
+        return Some((ndk_root(&path).unwrap_or(path), var_name.to_string()));
+    }
+
+    let sdk_vars = ["ANDROID_HOME", "ANDROID_SDK_ROOT", "ANDROID_SDK_HOME"];
+    if let Some((var_name, sdk_path)) = find_first_consistent_var_set(&sdk_vars, shell) {
+        let ndk_path = PathBuf::from(&sdk_path).join("ndk");
+        if let Some(v) = highest_version_ndk_in_path(&ndk_path) {
+            return Some((v, var_name.to_string()));
+        }
+
+        // Older SDKs and Nix's androidenv install a single NDK to `ndk-bundle`
+        let ndk_path = PathBuf::from(&sdk_path).join("ndk-bundle");
+        if has_toolchains(&ndk_path) {
+            return Some((ndk_path, var_name.to_string()));
+        }
+    }
+
+    let ndk_dir = default_ndk_dir();
+    if let Some(path) = highest_version_ndk_in_path(&ndk_dir) {
+        return Some((path, "standard location".to_string()));
+    }
+
+    package_manager_ndk_dirs()
+        .into_iter()
+        .find_map(|path| ndk_root(&path))
+        .map(|path| (path, "package manager location".to_string()))
+}
+
+fn has_toolchains(path: &Path) -> bool {
+    path.join("toolchains")
+        .join("llvm")
+        .join("prebuilt")
+        .is_dir()
+}
+
+/// Resolve a user-provided NDK path to the directory containing `toolchains`.
+///
+/// This is either the path itself, the `Contents/NDK` directory of a macOS app bundle
+/// (as installed by Homebrew), or the highest versioned NDK within the path.
+fn ndk_root(path: &Path) -> Option<PathBuf> {
+    if has_toolchains(path) {
+        return Some(path.to_path_buf());
+    }
+
+    let bundle_path = path.join("Contents").join("NDK");
+    if has_toolchains(&bundle_path) {
+        return Some(bundle_path);
+    }
+
+    highest_version_ndk_in_path(path)
+}
+
+/// Locations package managers install the NDK to outside of an Android SDK
+fn package_manager_ndk_dirs() -> Vec<PathBuf> {
+    #[cfg(target_os = "macos")]
+    let dirs = vec![
+        PathBuf::from("/opt/homebrew/share/android-ndk"),
+        PathBuf::from("/usr/local/share/android-ndk"),
+    ];
+
+    #[cfg(not(target_os = "macos"))]
+    let dirs = vec![];
+
+    dirs
+}
+
+fn output_dir_from_env() -> Option<PathBuf> {
+    env::var_os(OUTPUT_DIR_ENV)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
+/// How the manifest's defaults and the flags that override them fit together, for `--help`.
+const MANIFEST_HELP: &str = "Configuration:
+  The targets, platform and output directory default to `targets`, `platform` and `output_dir`
+  in the [package.metadata.ndk] table of Cargo.toml, where a relative output_dir is relative to
+  Cargo.toml. Flags take precedence over environment variables, which take precedence over the
+  manifest, which takes precedence over the built-in defaults.";
+
+fn print_usage() {
+    println!("cargo-ndk <https://github.com/bbqsrc/cargo-ndk>\n\nUsage: cargo ndk [OPTIONS] <CARGO_ARGS>\n       cargo ndk init [--name NAME] [--targets TARGETS] [PATH]\n");
+    println!("{}", Args::usage());
+    println!("\n{MANIFEST_HELP}");
+    println!("\n{}", crate::env_vars::help());
 }
 
-impl From<&str> for BuildMode {
-    fn from(profile: &str) -> Self {
-        match profile {
-            "dev" => BuildMode::Debug,
-            "release" => BuildMode::Release,
-            _ => BuildMode::Profile(profile.to_string()),
+fn print_usage_env() {
+    println!(
+        "cargo-ndk-env <https://github.com/bbqsrc/cargo-ndk>\n\nUsage: cargo ndk-env [OPTIONS]\n"
+    );
+    println!("{}", ArgsEnv::usage());
+    println!("\n{}", crate::env_vars::help());
+}
+
+fn default_ndk_dir() -> PathBuf {
+    #[cfg(windows)]
+    let dir = pathos::user::local_dir()
+        .unwrap()
+        .to_path_buf()
+        .join("Android")
+        .join("sdk")
+        .join("ndk");
+
+    #[cfg(target_os = "linux")]
+    let dir = pathos::xdg::home_dir()
+        .unwrap()
+        .join("Android")
+        .join("Sdk")
+        .join("ndk");
+
+    #[cfg(target_os = "macos")]
+    let dir = pathos::user::home_dir()
+        .unwrap()
+        .join("Library")
+        .join("Android")
+        .join("sdk")
+        .join("ndk");
+
+    #[cfg(not(any(target_os = "macos", target_os = "linux", target_os = "windows")))]
+    let dir = PathBuf::new();
+
+    dir
+}
+
+fn derive_ndk_version(path: &Path) -> anyhow::Result<Version> {
+    // Homebrew's NDK is an app bundle, so also look inside it before giving up.
+    let data = fs::read_to_string(path.join("source.properties")).or_else(|e| {
+        fs::read_to_string(path.join("Contents").join("NDK").join("source.properties"))
+            .map_err(|_| e)
+    })?;
+    for line in data.split('\n') {
+        if line.starts_with("Pkg.Revision") {
+            let mut chunks = line.split(" = ");
+            let _ = chunks
+                .next()
+                .ok_or_else(|| io::Error::new(ErrorKind::Other, "No chunk"))?;
+            let version = chunks
+                .next()
+                .ok_or_else(|| io::Error::new(ErrorKind::Other, "No chunk"))?;
+            let version = match Version::parse(version) {
+                Ok(v) => v,
+                Err(_e) => {
+                    return Err(anyhow::anyhow!(format!(
+                        "Could not parse NDK version. Got: '{}'",
+                        version
+                    )));
+                }
+            };
+            return Ok(version);
+        }
+    }
+
+    Err(anyhow::anyhow!("Could not find Pkg.Revision in given path"))
+}
+
+/// Remove `-t`/`--target` options from cargo arguments, up to any `--`, and parse them as targets.
+fn take_targets(cargo_args: &mut Vec<String>) -> Result<Vec<TargetArg>, String> {
+    let mut targets = Vec::new();
+    let mut i = 0;
+    while i < cargo_args.len() && cargo_args[i] != "--" {
+        let value = match cargo_args[i].as_str() {
+            "-t" | "--target" => {
+                if i + 1 >= cargo_args.len() {
+                    return Err(format!("missing value for {}", cargo_args[i]));
+                }
+                cargo_args.remove(i);
+                cargo_args.remove(i)
+            }
+            arg if arg.starts_with("--target=") => {
+                cargo_args.remove(i)["--target=".len()..].to_string()
+            }
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        targets.push(value.parse::<TargetArg>()?);
+    }
+    Ok(targets)
+}
+
+/// A target given with `-t`, optionally with the platform level to build it for, as in
+/// `arm64-v8a:30`.
+#[derive(Debug, Clone)]
+struct TargetArg {
+    target: Target,
+    platform: Option<u8>,
+}
+
+impl FromStr for TargetArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (target, platform) = match s.split_once(':') {
+            Some((target, level)) => match level.parse::<u8>() {
+                Ok(level) => (target, Some(level)),
+                Err(_) => return Err(format!("invalid platform level '{level}' in '{s}'")),
+            },
+            None => (s, None),
+        };
+        Ok(TargetArg {
+            target: target.parse()?,
+            platform,
+        })
+    }
+}
+
+/// The platform level each target is built for: the one from `--platform` or the manifest,
+/// unless the target was given with its own, as in `-t arm64-v8a:30`.
+struct Platforms {
+    default: u8,
+    by_target: Vec<(Target, u8)>,
+}
+
+impl Platforms {
+    fn get(&self, target: &Target) -> u8 {
+        self.by_target
+            .iter()
+            .find(|(t, _)| t == target)
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// Raise the platform level of any of `targets` that doesn't exist at its level to the
+    /// lowest one it does, returning each target raised with the level it had.
+    fn raise_to_minimum(&mut self, targets: &[Target]) -> Vec<(Target, u8)> {
+        let mut raised = Vec::new();
+        for target in targets {
+            let level = self.get(target);
+            match target.min_platform() {
+                Some(min) if level < min => {
+                    self.by_target.retain(|(t, _)| t != target);
+                    self.by_target.push((target.clone(), min));
+                    raised.push((target.clone(), level));
+                }
+                _ => {}
+            }
+        }
+        raised
+    }
+}
+
+/// The NDK each target is built with: the detected one, unless `--ndk-home` gives another.
+struct Ndks {
+    default: (PathBuf, Version),
+    by_target: Vec<(Target, PathBuf, Version)>,
+}
+
+impl Ndks {
+    fn get(&self, target: &Target) -> (&Path, &Version) {
+        self.by_target
+            .iter()
+            .find(|(t, _, _)| t == target)
+            .map(|(_, path, version)| (path.as_path(), version))
+            .unwrap_or((&self.default.0, &self.default.1))
+    }
+
+    fn home(&self, target: &Target) -> &Path {
+        self.get(target).0
+    }
+}
+
+/// Detect the version of the NDK at `ndk_home` and check that it can be used, exiting if not.
+fn check_ndk(
+    shell: &mut Shell,
+    args: &Args,
+    ndk_home: &Path,
+    ndk_detection_method: &str,
+) -> anyhow::Result<Version> {
+    let ndk_version = if args.skip_ndk_version_check {
+        ASSUMED_NDK_VERSION
+    } else {
+        match derive_ndk_version(ndk_home) {
+            Ok(v) => v,
+            Err(e) => {
+                shell.warn(format!(
+                    "Error detecting NDK version for path {}: {}",
+                    ndk_home.display(),
+                    e
+                ))?;
+                match infer_ndk_version(ndk_home) {
+                    Some((v, source)) => {
+                        shell.note(format!("using NDK v{v} inferred from {source}"))?;
+                        v
+                    }
+                    None => {
+                        shell.note(format!(
+                        "assuming a supported NDK (v{ASSUMED_NDK_VERSION}); pass --skip-ndk-version-check to silence this"
+                    ))?;
+                        ASSUMED_NDK_VERSION
+                    }
+                }
+            }
+        }
+    };
+
+    shell.verbose(|shell| {
+        shell.status_with_color(
+            "Detected",
+            format!(
+                "NDK v{} ({}) [{}]",
+                ndk_version,
+                ndk_home.display(),
+                ndk_detection_method
+            ),
+            termcolor::Color::Cyan,
+        )
+    })?;
+
+    if args.legacy_toolchain && ndk_version.major >= 23 {
+        shell.error(format!(
+            "--legacy-toolchain is only for NDK r22 and earlier, but NDK v{ndk_version} was found"
+        ))?;
+        std::process::exit(1);
+    }
+
+    if ndk_version.major < 23 {
+        let message = format!(
+            "NDK v{} ({}) [{}] is older than r23, which is not supported",
+            ndk_version,
+            ndk_home.display(),
+            ndk_detection_method
+        );
+        if args.legacy_toolchain {
+            shell.warn(message)?;
+        } else if args.allow_old_ndk {
+            shell.warn(message)?;
+            shell.note("continuing because of --allow-old-ndk; the build may fail")?;
+            shell.note(
+                "pass --legacy-toolchain too if the NDK lacks the llvm-ar/llvm-strip tools",
+            )?;
+        } else {
+            shell.error(message)?;
+            shell.note(
+                "Install an up-to-date version of the NDK, or pass --allow-old-ndk to try anyway.",
+            )?;
+            std::process::exit(1);
+        }
+    }
+
+    if ndk_home.to_string_lossy().contains(char::is_whitespace) {
+        shell.warn(format!(
+            "the NDK path contains spaces, which some build scripts don't handle: {}",
+            ndk_home.display()
+        ))?;
+        shell.note(
+            "if a C dependency fails to build, try an NDK installed to a path without spaces",
+        )?;
+    }
+
+    let host_tag = crate::cargo::host_tag(ndk_home);
+    let prebuilt_dirs = crate::cargo::prebuilt_dirs(ndk_home);
+    if !prebuilt_dirs.contains(&host_tag) {
+        shell.error(format!(
+            "NDK at {} has no prebuilt toolchain for this host ({})",
+            ndk_home.display(),
+            host_tag
+        ))?;
+        if prebuilt_dirs.is_empty() {
+            shell.note("no toolchains/llvm/prebuilt directory was found; is this an NDK?")?;
+        } else {
+            shell.note(format!(
+                "the NDK only has prebuilt toolchains for: {}",
+                prebuilt_dirs.join(", ")
+            ))?;
+            shell.note("install the NDK built for this host OS and architecture")?;
+        }
+        std::process::exit(1);
+    }
+
+    Ok(ndk_version)
+}
+
+/// The version assumed for an NDK whose version can't be detected, being the oldest supported.
+const ASSUMED_NDK_VERSION: Version = Version::new(23, 0, 0);
+
+/// Infer the version of an NDK lacking `source.properties`, returning where it was found.
+///
+/// This checks for a `.ndk-version` marker file, then for a versioned directory name, such
+/// as `26.1.10909125` as installed by the SDK manager or `android-ndk-r26b` from a download.
+fn infer_ndk_version(path: &Path) -> Option<(Version, &'static str)> {
+    if let Ok(data) = fs::read_to_string(path.join(".ndk-version")) {
+        if let Ok(version) = Version::parse(data.trim()) {
+            return Some((version, ".ndk-version"));
+        }
+    }
+
+    let name = path.file_name()?.to_str()?;
+    if let Ok(version) = Version::parse(name) {
+        return Some((version, "the directory name"));
+    }
+
+    let major = name.rsplit_once("-r")?.1;
+    let major = major.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    major
+        .parse()
+        .ok()
+        .map(|major| (Version::new(major, 0, 0), "the directory name"))
+}
+
+/// The variables in `env` whose values can't be output exactly in `format`, as they aren't valid
+/// UTF-8. Only NUL-separated output on Unix can hold any value, as it's written as bytes.
+fn inexact_env_values(env: &BTreeMap<String, OsString>, format: EnvFormat) -> Vec<&str> {
+    if cfg!(unix) && format == EnvFormat::Null {
+        return Vec::new();
+    }
+    env.iter()
+        .filter(|(_, v)| v.to_str().is_none())
+        .map(|(k, _)| k.as_str())
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EnvFormat {
+    Bash,
+    PowerShell,
+    Json,
+    Null,
+}
+
+impl FromStr for EnvFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "bash" => EnvFormat::Bash,
+            "powershell" => EnvFormat::PowerShell,
+            "json" => EnvFormat::Json,
+            "null" => EnvFormat::Null,
+            _ => return Err(format!("Unsupported format: '{s}'")),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum OutputDirBase {
+    #[default]
+    Cwd,
+    Manifest,
+    WorkspaceRoot,
+}
+
+impl FromStr for OutputDirBase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "cwd" => OutputDirBase::Cwd,
+            "manifest" => OutputDirBase::Manifest,
+            "workspace-root" => OutputDirBase::WorkspaceRoot,
+            _ => return Err(format!("Unsupported output dir base: '{s}'")),
+        })
+    }
+}
+
+/// A kind of artifact that can be copied to the output dir.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArtifactKind {
+    Cdylib,
+    Bin,
+    Example,
+}
+
+impl FromStr for ArtifactKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "cdylib" => ArtifactKind::Cdylib,
+            "bin" => ArtifactKind::Bin,
+            "example" => ArtifactKind::Example,
+            _ => return Err(format!("Unsupported artifact kind: '{s}'")),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum BuildMode {
+    Debug,
+    Release,
+    Profile(String),
+}
+
+impl Display for BuildMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            BuildMode::Debug => "debug",
+            BuildMode::Release => "release",
+            BuildMode::Profile(x) => x,
+        })
+    }
+}
+
+impl BuildMode {
+    /// The name of the profile in Cargo.toml, where the debug build is `dev`.
+    fn profile(&self) -> &str {
+        match self {
+            BuildMode::Debug => "dev",
+            BuildMode::Release => "release",
+            BuildMode::Profile(x) => x,
+        }
+    }
+}
+
+/// The profile settings `--optimize-size` gives the profile being built.
+const OPTIMIZE_SIZE_PROFILE: &[(&str, &str)] = &[
+    ("opt-level", "\"z\""),
+    ("lto", "true"),
+    ("codegen-units", "1"),
+    ("panic", "\"abort\""),
+];
+
+impl From<&str> for BuildMode {
+    fn from(profile: &str) -> Self {
+        match profile {
+            "dev" => BuildMode::Debug,
+            "release" => BuildMode::Release,
+            _ => BuildMode::Profile(profile.to_string()),
+        }
+    }
+}
+
+fn is_supported_rustc_version() -> bool {
+    version_check::is_min_version("1.68.0").unwrap_or_default()
+}
+
+fn panic_hook(info: &PanicHookInfo<'_>) {
+    fn _attempt_shell(lines: &[String]) -> Result<(), anyhow::Error> {
+        let mut shell = Shell::new();
+        shell.error("cargo-ndk panicked! Generating report...")?;
+        for line in lines {
+            println!("{}", line);
+        }
+        shell.error("end of panic report. Please report the above to: <https://github.com/bbqsrc/cargo-ndk/issues>")?;
+        Ok(())
+    }
+
+    let location = info.location().unwrap();
+    let msg = match info.payload().downcast_ref::<&'static str>() {
+        Some(s) => *s,
+        None => match info.payload().downcast_ref::<String>() {
+            Some(s) => &s[..],
+            None => "Box<dyn Any>",
+        },
+    };
+
+    let env = std::env::vars()
+        .map(|(x, y)| format!("{}={:?}", x, y))
+        .collect::<Vec<_>>();
+    let args = std::env::args().collect::<Vec<_>>();
+
+    let lines = vec![
+        format!("location: {location}"),
+        format!("message: {msg}"),
+        format!("args: {args:?}"),
+        format!(
+            "pwd: {}",
+            std::env::current_dir()
+                .map(|x| x.display().to_string())
+                .unwrap_or_else(|_| "<unknown>".to_string())
+        ),
+        format!("env:\n  {}", env.join("\n  ")),
+    ];
+
+    if _attempt_shell(&lines).is_err() {
+        // Last ditch attempt
+        for line in lines {
+            eprintln!("{}", line);
+        }
+    }
+}
+
+pub fn run_env(args: Vec<String>) -> anyhow::Result<()> {
+    if args.contains(&"-h".into()) || args.contains(&"--help".into()) {
+        print_usage_env();
+        std::process::exit(0);
+    }
+
+    let color = args
+        .iter()
+        .position(|x| x == "--color")
+        .and_then(|p| args.get(p + 1))
+        .map(|x| &**x);
+
+    let verbosity = if args.contains(&"-q".into()) {
+        Verbosity::Quiet
+    } else if args.contains(&"-vv".into()) {
+        Verbosity::VeryVerbose
+    } else if args.contains(&"-v".into()) || args.contains(&"--verbose".into()) {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    };
+
+    let mut shell = Shell::new();
+    shell.set_verbosity(verbosity);
+    shell.set_color_choice(color)?;
+
+    let args = match ArgsEnv::parse_args(&args, gumdrop::ParsingStyle::StopAtFirstFree) {
+        Ok(args) if args.help => {
+            print_usage();
+            std::process::exit(0);
+        }
+        Ok(args) if args.version => {
+            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            std::process::exit(0);
+        }
+        Ok(args) => args,
+        Err(e) => {
+            shell.error(e)?;
+            std::process::exit(2);
+        }
+    };
+
+    crate::cargo::check_sibling_binaries(&mut shell)?;
+
+    let (ndk_home, _ndk_detection_method) = match derive_ndk_path(&mut shell) {
+        Some((path, method)) => (path, method),
+        None => {
+            shell.error("Could not find any NDK.")?;
+            shell.note(
+                "Set the environment ANDROID_NDK_HOME to your NDK installation's root directory,\nor install the NDK using Android Studio."
+            )?;
+            std::process::exit(1);
+        }
+    };
+
+    let format = match args.format {
+        Some(format) => format,
+        None if args.json => EnvFormat::Json,
+        None if args.powershell => EnvFormat::PowerShell,
+        None => match env::var(OUT_FORMAT_ENV) {
+            Ok(format) if !format.is_empty() => match format.parse() {
+                Ok(format) => format,
+                Err(e) => {
+                    shell.error(format!("{e} in {OUT_FORMAT_ENV}"))?;
+                    std::process::exit(2);
+                }
+            },
+            _ => EnvFormat::Bash,
+        },
+    };
+
+    if args.include_internal && format != EnvFormat::Json {
+        shell.warn("--include-internal only has an effect with JSON output")?;
+    }
+    let include_internal = args.include_internal && format == EnvFormat::Json;
+
+    // Try command line, then config. Config falls back to defaults in any case.
+    let env = build_env(
+        args.target.triple(),
+        &ndk_home,
+        args.platform.unwrap_or(Ndk::default().platform),
+        args.bindgen,
+        args.legacy_toolchain,
+        !args.no_pkg_config_setup,
+        openssl_dir(args.openssl_sysroot.as_deref(), &args.target).as_deref(),
+        !args.no_linker_override,
+        &[],
+        &TargetCpu::default(),
+    )
+    .into_iter()
+    .filter(|(k, _)| include_internal || !k.starts_with('_'))
+    .collect::<BTreeMap<_, _>>();
+
+    let inexact = inexact_env_values(&env, format);
+    if !inexact.is_empty() {
+        shell.warn(format!(
+            "the values of {} aren't valid UTF-8, and can't be output exactly",
+            inexact.join(", ")
+        ))?;
+    }
+
+    match format {
+        EnvFormat::Json => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(
+                    &env.into_iter()
+                        .map(|(k, v)| (k, v.to_string_lossy().into_owned()))
+                        .collect::<BTreeMap<_, _>>()
+                )
+                .unwrap()
+            );
+        }
+        EnvFormat::PowerShell => {
+            for (k, v) in env {
+                println!("${{env:{}}}={:?}", k, v);
+            }
+            println!();
+            println!("# To import with PowerShell:");
+            println!("#     cargo ndk-env --powershell | Out-String | Invoke-Expression");
+        }
+        EnvFormat::Null => {
+            let mut stdout = io::stdout().lock();
+            for (k, v) in env {
+                write!(stdout, "{k}=")?;
+                #[cfg(unix)]
+                stdout.write_all(std::os::unix::ffi::OsStrExt::as_bytes(v.as_os_str()))?;
+                #[cfg(not(unix))]
+                write!(stdout, "{}", v.to_string_lossy())?;
+                stdout.write_all(b"\0")?;
+            }
+            stdout.flush()?;
+        }
+        EnvFormat::Bash => {
+            for (k, v) in env {
+                println!("export {}={:?}", k.to_uppercase().replace('-', "_"), v);
+            }
+            println!();
+            println!("# To import with bash/zsh/etc:");
+            println!("#     source <(cargo ndk-env)");
+        }
+    }
+
+    Ok(())
+}
+
+pub fn run(args: Vec<String>) -> anyhow::Result<()> {
+    // Anything after `--` is for the binaries cargo runs (e.g. libtest's `-q` or `--help`), so
+    // only look for our own flags before it.
+    let flags = &args[..args.iter().position(|x| x == "--").unwrap_or(args.len())];
+
+    // `cargo ndk init --help` is handled by `init` itself
+    let is_init = args
+        .first()
+        .is_some_and(|arg| arg == "init" || arg == "new");
+    if args.is_empty()
+        || (!is_init && (flags.contains(&"-h".into()) || flags.contains(&"--help".into())))
+    {
+        print_usage();
+        std::process::exit(0);
+    }
+
+    let verbosity = if flags.contains(&"-q".into()) {
+        Verbosity::Quiet
+    } else if flags.contains(&"-vv".into()) {
+        Verbosity::VeryVerbose
+    } else if flags.contains(&"-v".into()) || flags.contains(&"--verbose".into()) {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    };
+
+    let color = flags
+        .iter()
+        .position(|x| x == "--color")
+        .and_then(|p| flags.get(p + 1))
+        .map(|x| &**x);
+
+    let mut shell = Shell::new();
+    shell.set_verbosity(verbosity);
+    shell.set_color_choice(color)?;
+
+    if std::env::var_os("CARGO_NDK_NO_PANIC_HOOK").is_none() {
+        panic::set_hook(Box::new(panic_hook));
+    }
+
+    if !is_supported_rustc_version() {
+        shell.error("Rust compiler is too old and not supported by cargo-ndk.")?;
+        shell.note("Upgrade Rust to at least v1.68.0.")?;
+        std::process::exit(1);
+    }
+
+    let build_mode = if flags.contains(&"--release".into()) {
+        BuildMode::Release
+    } else if let Some(i) = flags.iter().position(|x| x == "--profile") {
+        flags
+            .get(i + 1)
+            .map(|p| BuildMode::from(p.as_str()))
+            .unwrap_or(BuildMode::Debug)
+    } else {
+        flags
+            .iter()
+            .find_map(|a| a.strip_prefix("--profile=").map(BuildMode::from))
+            .unwrap_or(BuildMode::Debug)
+    };
+
+    // A leading `+toolchain` would otherwise end cargo-ndk's own flags, as the first free argument,
+    // so it's taken off first, for `cargo ndk +nightly -t arm64-v8a build`.
+    let raw_args = &args;
+    let (leading_toolchain, args) = match raw_args.split_first() {
+        Some((first, rest)) if first.starts_with('+') => (Some(first[1..].to_string()), rest),
+        _ => (None, &args[..]),
+    };
+
+    let mut args = match Args::parse_args(args, gumdrop::ParsingStyle::StopAtFirstFree) {
+        Ok(args) if args.help => {
+            print_usage();
+            std::process::exit(0);
+        }
+        Ok(args) if args.version => {
+            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            std::process::exit(0);
+        }
+        Ok(args) => args,
+        Err(e) => {
+            shell.error(e)?;
+            std::process::exit(2);
+        }
+    };
+
+    if args.output_dir.is_none() {
+        args.output_dir = output_dir_from_env();
+    }
+
+    if args.list_env_vars {
+        return crate::env_vars::print(args.json);
+    }
+
+    // Like cargo, accept a `+toolchain` before the cargo subcommand, e.g. `cargo ndk +nightly build`.
+    let toolchain = match (args.cargo_args.first(), leading_toolchain) {
+        (Some(arg), Some(leading)) if arg.starts_with('+') => {
+            shell.error(format!(
+                "two toolchains were given, +{leading} and {arg}; only give one"
+            ))?;
+            std::process::exit(2);
+        }
+        (Some(arg), None) if arg.starts_with('+') => {
+            Some(args.cargo_args.remove(0)[1..].to_string())
+        }
+        (_, leading) => leading,
+    };
+
+    // `cargo ndk run` builds a binary and runs it on a device, so also accept the target after
+    // the subcommand, as in `cargo ndk run -t arm64-v8a --bin foo`.
+    let is_run = args.cargo_args.first().is_some_and(|arg| arg == "run");
+    if is_run {
+        match take_targets(&mut args.cargo_args) {
+            Ok(targets) => args.target.extend(targets),
+            Err(e) => {
+                shell.error(e)?;
+                std::process::exit(2);
+            }
+        }
+    }
+
+    // `cargo ndk init` and `cargo ndk new` scaffold a crate, so don't need an NDK or a manifest
+    if let Some(subcommand @ ("init" | "new")) = args.cargo_args.first().map(String::as_str) {
+        let subcommand = subcommand.to_string();
+        return crate::init::run(
+            &mut shell,
+            &subcommand,
+            &args.cargo_args[1..],
+            &args
+                .target
+                .iter()
+                .map(|arg| arg.target.clone())
+                .collect::<Vec<_>>(),
+            args.platform,
+        );
+    }
+
+    let mut extra_env = match args
+        .env
+        .iter()
+        .map(|pair| {
+            pair.split_once('=')
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .ok_or_else(|| format!("invalid --env value '{pair}', expected KEY=VALUE"))
+        })
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(v) => v,
+        Err(e) => {
+            shell.error(e)?;
+            std::process::exit(2);
+        }
+    };
+
+    if let Some(path) = args.env_file.as_deref() {
+        let snapshot = fs::read_to_string(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| Ok(serde_json::from_str::<BTreeMap<String, String>>(&data)?));
+        match snapshot {
+            Ok(snapshot) => {
+                shell.warn(format!(
+                    "using the environment from {}; the build may not behave as it would otherwise if its paths differ from this machine's",
+                    path.display()
+                ))?;
+                // `--env` still takes priority, so a replayed environment can be tweaked
+                extra_env.splice(0..0, snapshot);
+            }
+            Err(e) => {
+                shell.error(format!(
+                    "failed to read --env-file {}: {}",
+                    path.display(),
+                    e
+                ))?;
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let junit_path = match args.test_report.as_deref().map(|x| x.split_once('=')) {
+        None => None,
+        Some(Some(("junit", path))) => Some(PathBuf::from(path)),
+        Some(_) => {
+            shell.error("invalid --test-report value, expected junit=PATH")?;
+            std::process::exit(2);
+        }
+    };
+
+    if args.cargo_args.is_empty()
+        && !args.dump_clang_cmdline
+        && !args.print_sysroot_libs
+        && !args.print_env_diff
+    {
+        shell.error("No args found to pass to cargo!")?;
+        shell.note("You still need to specify build arguments to cargo to achieve anything. :)")?;
+        std::process::exit(1);
+    }
+
+    // Build scripts and proc-macros are built for the host of the rustc cargo runs, which is fixed
+    // per toolchain, so another host means another toolchain, e.g. `stable-x86_64-unknown-linux-musl`
+    // rather than the `-gnu` one rustup picked in a musl container.
+    let toolchain = match args.host_target.as_deref() {
+        None => toolchain,
+        Some(host) if host.parse::<Target>().is_ok() => {
+            shell.error(format!(
+                "--host-target {host} is an Android target, but build scripts run on the host"
+            ))?;
+            std::process::exit(2);
+        }
+        Some(_) if args.cargo.is_some() => {
+            shell.error("--host-target can't be used together with --cargo")?;
+            shell.note("the host is decided by the rustc that cargo runs")?;
+            std::process::exit(2);
+        }
+        Some(host) => {
+            let toolchain = match toolchain {
+                Some(toolchain) => toolchain,
+                None => match rustup_active_toolchain() {
+                    Ok(toolchain) => toolchain,
+                    Err(e) => {
+                        shell.error(
+                            "--host-target needs rustup to pick the toolchain for the host",
+                        )?;
+                        shell.error(e)?;
+                        std::process::exit(1);
+                    }
+                },
+            };
+            match rustc_host(&toolchain) {
+                Some(current) if current == host => {
+                    shell.verbose(|shell| {
+                        shell.note(format!("toolchain {toolchain} is already for host {host}"))
+                    })?;
+                    Some(toolchain)
+                }
+                current => {
+                    let channel = current
+                        .and_then(|current| {
+                            toolchain
+                                .strip_suffix(&format!("-{current}"))
+                                .map(str::to_string)
+                        })
+                        .unwrap_or(toolchain);
+                    let toolchain = format!("{channel}-{host}");
+                    shell.verbose(|shell| {
+                        shell.status_with_color(
+                            "Using",
+                            format!("toolchain {toolchain} for host {host}"),
+                            termcolor::Color::Cyan,
+                        )
+                    })?;
+                    Some(toolchain)
+                }
+            }
+        }
+    };
+
+    // Given to cargo as --config, so they apply over the profile's own settings in Cargo.toml
+    if args.optimize_size {
+        if args.no_strip {
+            shell
+                .warn("--no-strip keeps the symbols that --optimize-size would otherwise strip")?;
+        }
+        let profile = build_mode.profile();
+        let config = OPTIMIZE_SIZE_PROFILE
+            .iter()
+            .flat_map(|(key, value)| {
+                [
+                    "--config".to_string(),
+                    format!("profile.{profile}.{key}={value}"),
+                ]
+            })
+            .collect::<Vec<_>>();
+        let position = args
+            .cargo_args
+            .iter()
+            .position(|arg| arg == "--")
+            .unwrap_or(args.cargo_args.len());
+        args.cargo_args.splice(position..position, config);
+    }
+
+    let cargo_bin = match (args.cargo.as_ref(), toolchain.as_deref()) {
+        (Some(_), Some(toolchain)) => {
+            shell.error(format!("--cargo can't be used together with +{toolchain}"))?;
+            std::process::exit(2);
+        }
+        (None, Some(toolchain)) => match rustup_which_cargo(toolchain) {
+            Ok(path) => {
+                // Make sure the rustup proxies that cargo calls, like rustc, agree on the toolchain.
+                extra_env.push(("RUSTUP_TOOLCHAIN".to_string(), toolchain.to_string()));
+                path
+            }
+            Err(e) => {
+                shell.error(format!("failed to find cargo for toolchain {toolchain}"))?;
+                shell.error(e)?;
+                shell.note(format!(
+                    "install it with `rustup toolchain install {toolchain}`"
+                ))?;
+                std::process::exit(1);
+            }
+        },
+        (Some(path), None) => {
+            // A bare name is looked up on the PATH, so only check paths that point somewhere.
+            if path.components().count() > 1 && !is_executable(path) {
+                shell.error(format!(
+                    "--cargo {} is not an executable file",
+                    path.display()
+                ))?;
+                std::process::exit(2);
+            }
+            path.clone()
+        }
+        (None, None) => env::var_os("CARGO")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("cargo")),
+    };
+
+    let metadata = match MetadataCommand::new()
+        .cargo_path(&cargo_bin)
+        .no_deps()
+        .exec()
+    {
+        Ok(v) => v,
+        Err(e) => {
+            shell.error("Failed to load Cargo.toml in current directory.")?;
+            shell.error(e)?;
+            std::process::exit(1);
+        }
+    };
+
+    let out_dir = metadata.target_directory.clone();
+
+    // `cargo ndk watch` runs cargo-ndk again with the same arguments, less `watch`, on every change
+    if args.cargo_args.first().is_some_and(|arg| arg == "watch") {
+        let mut watch_args = raw_args.clone();
+        if let Some(i) = watch_args.iter().position(|arg| arg == "watch") {
+            watch_args.remove(i);
+        }
+        let flags = match watch_args.first() {
+            Some(first) if first.starts_with('+') => &watch_args[1..],
+            _ => &watch_args[..],
+        };
+        let output_dir = Args::parse_args(flags, gumdrop::ParsingStyle::StopAtFirstFree)
+            .ok()
+            .and_then(|args| args.output_dir)
+            .or_else(output_dir_from_env)
+            .or_else(|| {
+                let manifest = env::current_dir().ok()?.join("Cargo.toml");
+                crate::meta::config(&manifest, &build_mode).ok()?.output_dir
+            });
+        return crate::watch::run(
+            &mut shell,
+            &watch_args,
+            metadata.workspace_root.as_std_path(),
+            &[Some(out_dir.into_std_path_buf()), output_dir]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    // We used to check for NDK_HOME, so we'll keep doing that. But we'll also try ANDROID_NDK_HOME
+    // and $ANDROID_SDK_HOME/ndk as this is how Android Studio configures the world
+    crate::cargo::check_sibling_binaries(&mut shell)?;
+
+    let (ndk_home, ndk_detection_method) = match derive_ndk_path(&mut shell) {
+        Some((path, method)) => (path, method),
+        None => {
+            shell.error("Could not find any NDK.")?;
+            shell.note(
+                "Set the environment ANDROID_NDK_HOME to your NDK installation's root directory,\nor install the NDK using Android Studio."
+            )?;
+            std::process::exit(1);
+        }
+    };
+
+    let ndk_version = check_ndk(&mut shell, &args, &ndk_home, &ndk_detection_method)?;
+
+    // NDKs for particular targets, each checked the same way as the detected one
+    let mut ndks = Ndks {
+        default: (ndk_home.clone(), ndk_version.clone()),
+        by_target: Vec::new(),
+    };
+    for mapping in &args.ndk_home {
+        let parsed = mapping
+            .split_once('=')
+            .ok_or_else(|| format!("invalid --ndk-home value '{mapping}', expected TARGET=PATH"))
+            .and_then(|(target, path)| Ok((target.parse::<Target>()?, PathBuf::from(path))));
+        let (target, path) = match parsed {
+            Ok(v) => v,
+            Err(e) => {
+                shell.error(e)?;
+                std::process::exit(2);
+            }
+        };
+        let version = check_ndk(&mut shell, &args, &path, &format!("--ndk-home {target}"))?;
+        ndks.by_target.retain(|(t, _, _)| *t != target);
+        ndks.by_target.push((target, path, version));
+    }
+
+    let working_dir = env::current_dir().expect("current directory could not be resolved");
+
+    // Attempt to smartly determine exactly what package is being worked with. The following is the manifest priority:
+    //
+    // 1. --manifest-path in the command-line arguments
+    // 2. The manifest path of the package specified with `-p` for cargo.
+    // 3. The manifest path in the current working dir
+    let cargo_args = &args.cargo_args;
+    let cargo_manifest = args
+        .manifest_path
+        .or_else(|| {
+            if let Some(selected_package) = cargo_args
+                .iter()
+                .take_while(|arg| *arg != "--")
+                .position(|arg| arg == "-p" || arg == "--package")
+                .and_then(|idx| cargo_args.get(idx + 1))
+            {
+                let selected_package = metadata
+                    .packages
+                    .iter()
+                    .find(|p| &p.name == selected_package)
+                    .unwrap_or_else(|| panic!("unknown package: {selected_package}"));
+
+                Some(selected_package.manifest_path.as_std_path().to_path_buf())
+            } else {
+                None
+            }
+        })
+        .unwrap_or_else(|| working_dir.join("Cargo.toml"));
+    // Made absolute once here, as it's read and passed on from elsewhere
+    let cargo_manifest = crate::cargo::resolve_manifest(&cargo_manifest, &working_dir);
+
+    let config = match crate::meta::config(&cargo_manifest, &build_mode) {
+        Ok(v) => v,
+        Err(e) => {
+            shell.error("Failed loading manifest")?;
+            shell.error(e)?;
+            std::process::exit(1);
+        }
+    };
+
+    // `-o` and CARGO_NDK_OUTPUT_DIR take precedence over the manifest
+    if args.output_dir.is_none() {
+        args.output_dir = config.output_dir.clone();
+    }
+
+    let mut platforms = Platforms {
+        default: args.platform.unwrap_or(config.platform),
+        by_target: args
+            .target
+            .iter()
+            .filter_map(|arg| Some((arg.target.clone(), arg.platform?)))
+            .collect(),
+    };
+
+    // Try command line, then config. Config falls back to defaults in any case.
+    let targets_from_cli = !args.target.is_empty();
+    let targets = if targets_from_cli {
+        args.target.iter().map(|arg| arg.target.clone()).collect()
+    } else {
+        config.targets
+    };
+
+    // A `--target` given to cargo has to agree with cargo-ndk's, as cargo would otherwise build
+    // for both or complain about the same target given twice.
+    let user_targets = crate::cargo::user_targets(&args.cargo_args);
+    let targets = match user_targets.as_slice() {
+        // Without -t, a single Android triple given to cargo selects the target, as in
+        // `cargo ndk build --target aarch64-linux-android`.
+        [user] if !targets_from_cli => match user.parse::<Target>() {
+            Ok(target) if target.triple() == user => vec![target],
+            _ => targets,
+        },
+        _ => targets,
+    };
+    let triples = targets.iter().map(Target::triple).collect::<Vec<_>>();
+    if let Some(conflict) = crate::cargo::conflicting_target(&user_targets, &triples) {
+        let selected = targets
+            .iter()
+            .map(|t| format!("{t} ({})", t.triple()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        shell.error(format!(
+            "`--target {conflict}` given to cargo conflicts with the selected target(s): {selected}"
+        ))?;
+        match conflict.parse::<Target>() {
+            // An Android ABI name rather than a triple, which cargo wouldn't understand anyway
+            Ok(target) if target.triple() != conflict => shell.note(format!(
+                "cargo's --target takes a triple; select the ABI with `cargo ndk -t {target}` instead"
+            ))?,
+            _ => shell.note(
+                "select targets with -t before the cargo command, e.g. `cargo ndk -t arm64-v8a build`",
+            )?,
+        }
+        std::process::exit(2);
+    }
+
+    for (target, level) in platforms.raise_to_minimum(&targets) {
+        shell.warn(format!(
+            "{target} doesn't exist below platform level {}, so it is built at that level rather than {level}",
+            platforms.get(&target)
+        ))?;
+    }
+
+    if is_run && targets.len() > 1 {
+        shell.error("`cargo ndk run` can only run one target at a time")?;
+        shell.note("pick one with -t, e.g. `cargo ndk run -t arm64-v8a`")?;
+        std::process::exit(2);
+    }
+
+    let is_lock = args.cargo_args.first().is_some_and(|arg| arg == "lock");
+    if is_lock || args.locked {
+        let lock_path = metadata.workspace_root.as_std_path().join(LOCKFILE_NAME);
+        let current = NdkLock {
+            ndk_version: ndk_version.to_string(),
+            clang_version: crate::cargo::clang_version(&ndk_home),
+            host: crate::cargo::host_tag(&ndk_home),
+            platform: platforms.default,
+            // A target with its own platform level is recorded with it, as it was given
+            targets: targets
+                .iter()
+                .map(|t| match platforms.get(t) {
+                    level if level != platforms.default => format!("{t}:{level}"),
+                    _ => t.to_string(),
+                })
+                .collect(),
+        };
+
+        if is_lock {
+            current.write(&lock_path)?;
+            shell.status("Locked", format!("{}", lock_path.display()))?;
+            return Ok(());
+        }
+
+        let locked = match NdkLock::read(&lock_path) {
+            Ok(v) => v,
+            Err(e) => {
+                shell.error(format!("--locked was given, but {e:#}"))?;
+                shell.note("create the lockfile with `cargo ndk lock`")?;
+                std::process::exit(1);
+            }
+        };
+        let drift = locked.drift(&current);
+        if !drift.is_empty() {
+            for message in drift {
+                shell.error(message)?;
+            }
+            shell.note(format!(
+                "the environment doesn't match {}; run `cargo ndk lock` to update it",
+                lock_path.display()
+            ))?;
+            std::process::exit(1);
+        }
+    }
+
+    let target_cpu = TargetCpu {
+        cpu: args.target_cpu.clone(),
+        features: args
+            .target_feature
+            .iter()
+            .flat_map(|features| features.split(','))
+            .map(str::trim)
+            .filter(|feature| !feature.is_empty())
+            .map(|feature| {
+                if feature.starts_with(['+', '-']) {
+                    feature.to_string()
+                } else {
+                    format!("+{feature}")
+                }
+            })
+            .collect(),
+    };
+    if args.dump_clang_cmdline {
+        for target in targets.iter() {
+            dump_clang_cmdline(
+                target,
+                ndks.home(target),
+                platforms.get(target),
+                args.bindgen,
+                args.legacy_toolchain,
+                &target_cpu,
+            );
+        }
+        return Ok(());
+    }
+
+    if args.print_env_diff {
+        print_env_diff(
+            &targets,
+            &ndks,
+            &platforms,
+            args.bindgen,
+            args.legacy_toolchain,
+            !args.no_pkg_config_setup,
+            args.openssl_sysroot.as_deref(),
+            !args.no_linker_override,
+            &args.link_lib,
+            &target_cpu,
+            &extra_env,
+            args.json,
+        )?;
+        return Ok(());
+    }
+
+    if args.print_sysroot_libs {
+        print_sysroot_libs(
+            &mut shell,
+            &targets,
+            &ndks,
+            &platforms,
+            args.legacy_toolchain,
+            args.json,
+        )?;
+        return Ok(());
+    }
+
+    if args.json {
+        shell.warn("--json has no effect without --print-sysroot-libs, --print-env-diff or --list-env-vars")?;
+    }
+
+    // An NDK without a sysroot for an ABI or platform level only fails once the first crate links,
+    // possibly a long way into the build
+    if args.check_ndk_abi_support {
+        let unsupported = unsupported_targets(&targets, &ndks, &platforms, args.legacy_toolchain);
+        if !unsupported.is_empty() {
+            for (target, levels) in &unsupported {
+                shell.error(format!(
+                    "NDK v{} at {} has no sysroot for {target} at platform level {}",
+                    ndks.get(target).1,
+                    ndks.home(target).display(),
+                    platforms.get(target)
+                ))?;
+                match (levels.first(), levels.last()) {
+                    (Some(min), Some(max)) => shell.note(format!(
+                        "it has {target} for platform levels {min} to {max}"
+                    ))?,
+                    _ => shell.note(format!("it doesn't support {target} at all"))?,
+                }
+            }
+            std::process::exit(1);
         }
     }
-}
 
-fn is_supported_rustc_version() -> bool {
-    version_check::is_min_version("1.68.0").unwrap_or_default()
-}
+    if !args.link_lib.is_empty() {
+        if let Some(name) = args
+            .link_lib
+            .iter()
+            .find(|name| name.is_empty() || name.contains(char::is_whitespace))
+        {
+            shell.error(format!("invalid --link-lib value '{name}'"))?;
+            std::process::exit(2);
+        }
+        if args.no_linker_override {
+            shell.warn("--link-lib has no effect with --no-linker-override")?;
+        }
+        check_link_libs(
+            &mut shell,
+            &args.link_lib,
+            &targets,
+            &ndks,
+            &platforms,
+            args.legacy_toolchain,
+        )?;
+    }
 
-fn panic_hook(info: &PanicHookInfo<'_>) {
-    fn _attempt_shell(lines: &[String]) -> Result<(), anyhow::Error> {
-        let mut shell = Shell::new();
-        shell.error("cargo-ndk panicked! Generating report...")?;
-        for line in lines {
-            println!("{}", line);
+    // A missing target otherwise only shows up once cargo gets to the first crate, with an error
+    // about `core` that doesn't say how to fix it
+    if !args.skip_target_check && !args.print_cargo_cmd && !builds_std(&args.cargo_args) {
+        let triples = targets.iter().map(Target::triple).collect::<Vec<_>>();
+        let missing = missing_rust_targets(&triples, &extra_env).unwrap_or_default();
+        if !missing.is_empty() {
+            let mut rustup = std::process::Command::new("rustup");
+            rustup
+                .args(["target", "add"])
+                .args(&missing)
+                .envs(extra_env.iter().cloned());
+            if args.install_targets {
+                shell.status("Installing", format!("Rust targets {}", missing.join(", ")))?;
+                let status = rustup.status().context("failed to run rustup")?;
+                if !status.success() {
+                    shell.error("failed to install the missing Rust targets")?;
+                    std::process::exit(crate::process::exit_code(&status));
+                }
+            } else {
+                shell.error(format!(
+                    "the Rust standard library isn't installed for {}",
+                    missing.join(", ")
+                ))?;
+                shell.note("install it with this command, or pass --install-targets to do so:")?;
+                shell.note("")?;
+                // For `+toolchain` and `--host-target`, which may not be rustup's default
+                let toolchain = extra_env
+                    .iter()
+                    .find(|(k, _)| k == "RUSTUP_TOOLCHAIN")
+                    .map(|(_, toolchain)| format!("--toolchain {toolchain} "))
+                    .unwrap_or_default();
+                shell.note(format!(
+                    "    rustup target add {toolchain}{}",
+                    missing.join(" ")
+                ))?;
+                std::process::exit(1);
+            }
         }
-        shell.error("end of panic report. Please report the above to: <https://github.com/bbqsrc/cargo-ndk/issues>")?;
-        Ok(())
     }
 
-    let location = info.location().unwrap();
-    let msg = match info.payload().downcast_ref::<&'static str>() {
-        Some(s) => *s,
-        None => match info.payload().downcast_ref::<String>() {
-            Some(s) => &s[..],
-            None => "Box<dyn Any>",
-        },
-    };
+    crate::cargo::check_exported_platform(
+        &mut shell,
+        &targets
+            .iter()
+            .map(|target| (target.triple(), platforms.get(target)))
+            .collect::<Vec<_>>(),
+    )?;
 
-    let env = std::env::vars()
-        .map(|(x, y)| format!("{}={:?}", x, y))
-        .collect::<Vec<_>>();
-    let args = std::env::args().collect::<Vec<_>>();
+    if args.output_dir.is_none() && args.output_dir_base.is_some() {
+        shell.warn("--output-dir-base has no effect without --output-dir")?;
+    }
 
-    let lines = vec![
-        format!("location: {location}"),
-        format!("message: {msg}"),
-        format!("args: {args:?}"),
-        format!(
-            "pwd: {}",
-            std::env::current_dir()
-                .map(|x| x.display().to_string())
-                .unwrap_or_else(|_| "<unknown>".to_string())
-        ),
-        format!("env:\n  {}", env.join("\n  ")),
-    ];
+    if let Some(output_dir) = args.output_dir.take() {
+        args.output_dir = Some(match args.output_dir_base.unwrap_or_default() {
+            _ if output_dir.is_absolute() => output_dir,
+            OutputDirBase::Cwd => output_dir,
+            OutputDirBase::Manifest => cargo_manifest
+                .parent()
+                .unwrap_or(Path::new(""))
+                .join(output_dir),
+            OutputDirBase::WorkspaceRoot => {
+                // The metadata loaded earlier is for the current directory, which may be in a
+                // different workspace to the one --manifest-path points at.
+                match MetadataCommand::new()
+                    .cargo_path(&cargo_bin)
+                    .manifest_path(&cargo_manifest)
+                    .no_deps()
+                    .exec()
+                {
+                    Ok(metadata) => metadata.workspace_root.into_std_path_buf().join(output_dir),
+                    Err(e) => {
+                        shell.error(format!(
+                            "Failed to find the workspace root for {}.",
+                            cargo_manifest.display()
+                        ))?;
+                        shell.error(e)?;
+                        std::process::exit(1);
+                    }
+                }
+            }
+        });
+    }
 
-    if _attempt_shell(&lines).is_err() {
-        // Last ditch attempt
-        for line in lines {
-            eprintln!("{}", line);
+    let requested_packages = requested_packages(&args.cargo_args, &metadata, &cargo_manifest);
+
+    let has_artifact_dir = args
+        .cargo_args
+        .iter()
+        .take_while(|arg| arg.trim() != "--")
+        .any(|arg| {
+            ["--artifact-dir", "--out-dir"]
+                .iter()
+                .any(|flag| arg == flag || arg.starts_with(&format!("{flag}=")))
+        });
+    if has_artifact_dir {
+        if args.output_dir.is_some() {
+            shell.warn(
+                "both cargo's --artifact-dir and cargo-ndk's --output-dir are set; libraries will be copied to both",
+            )?;
+        }
+        if targets.len() > 1 {
+            shell.warn(
+                "cargo's --artifact-dir is shared by all targets, so each target's libraries will overwrite the last",
+            )?;
+            shell.note("use --output-dir to get a directory per ABI")?;
         }
     }
-}
 
-pub fn run_env(args: Vec<String>) -> anyhow::Result<()> {
-    if args.contains(&"-h".into()) || args.contains(&"--help".into()) {
-        print_usage_env();
-        std::process::exit(0);
+    if args.output_dir.is_none() && (args.size_manifest.is_some() || args.size_baseline.is_some()) {
+        shell.warn("library sizes are only recorded when using --output-dir")?;
     }
 
-    let color = args
+    let collect = match args
+        .collect
         .iter()
-        .position(|x| x == "--color")
-        .and_then(|p| args.get(p + 1))
-        .map(|x| &**x);
+        .flat_map(|kinds| kinds.split(','))
+        .map(str::trim)
+        .filter(|kind| !kind.is_empty())
+        .map(str::parse::<ArtifactKind>)
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(kinds) if kinds.is_empty() => vec![ArtifactKind::Cdylib],
+        Ok(kinds) => kinds,
+        Err(e) => {
+            shell.error(e)?;
+            std::process::exit(2);
+        }
+    };
+    if args.output_dir.is_none() && (!args.collect.is_empty() || args.push_all) {
+        shell.warn("--collect and --push-all have no effect without --output-dir")?;
+    }
 
-    let verbosity = if args.contains(&"-q".into()) {
-        Verbosity::Quiet
-    } else if args.contains(&"-vv".into()) {
-        Verbosity::VeryVerbose
-    } else if args.contains(&"-v".into()) || args.contains(&"--verbose".into()) {
-        Verbosity::Verbose
-    } else {
-        Verbosity::Normal
+    if args.output_dir.is_none() && args.compress.is_some() {
+        shell.warn("--compress has no effect without --output-dir")?;
+    }
+
+    if args.passthrough_output && args.stdout_json_lines {
+        shell.warn("--passthrough-output has no effect with --stdout-json-lines")?;
+    } else if args.passthrough_output && args.verbose_cc {
+        shell.warn("--verbose-cc has no effect with --passthrough-output")?;
+    }
+
+    let strip_mode = match args.strip {
+        Some(mode) => mode,
+        None => match env::var(STRIP_ENV) {
+            Ok(mode) if !mode.is_empty() => match mode.parse() {
+                Ok(mode) => mode,
+                Err(e) => {
+                    shell.error(format!("{e} in {STRIP_ENV}"))?;
+                    std::process::exit(2);
+                }
+            },
+            _ => StripMode::default(),
+        },
     };
+    if args.no_strip && args.strip.is_some() {
+        shell.warn("--strip has no effect with --no-strip")?;
+    }
+
+    let runs_binaries = crate::cargo::runs_binaries(&args.cargo_args);
+    if args.dry_run && !runs_binaries {
+        shell.warn(
+            "--dry-run has no effect with cargo commands that don't run binaries, such as build",
+        )?;
+    }
+
+    if args.output_dir.is_none() && args.copy_if_changed {
+        shell.warn("--copy-if-changed has no effect without --output-dir")?;
+    }
+
+    if args.output_dir.is_none() && args.symbols_dir.is_some() {
+        shell.warn("symbols are only kept for libraries copied with --output-dir")?;
+    }
+
+    if args.output_dir.is_none() && args.split_debug_info {
+        shell.warn("--split-debug-info has no effect without --output-dir")?;
+    } else if args.no_strip && args.split_debug_info {
+        shell.warn("--split-debug-info has no effect with --no-strip")?;
+    }
+    if !args.split_debug_info && args.debug_dir.is_some() {
+        shell.warn("--debug-dir has no effect without --split-debug-info")?;
+    }
+
+    let mut output_dir_missing = false;
+    if let Some(output_dir) = args.output_dir.as_ref() {
+        if let Err(e) = fs::create_dir_all(output_dir) {
+            if !args.allow_missing_output {
+                shell.error(format!("failed to create output dir, {e}"))?;
+                std::process::exit(1);
+            }
+            shell.warn(format!("failed to create output dir, {e}"))?;
+            shell.note("libraries will be left in the target directory")?;
+            output_dir_missing = true;
+        }
+    }
+
+    if let Some(output_dir) = args.output_dir.as_ref().filter(|_| !output_dir_missing) {
+        // Canonicalize because path is shared with build scripts that can run in a different current_dir.
+        let output_dir = match dunce::canonicalize(output_dir) {
+            Ok(p) => p,
+            Err(e) => {
+                shell.error(format!("failed to canonicalize output dir, {e}"))?;
+                if out_dir.is_absolute() {
+                    output_dir.clone()
+                } else {
+                    std::process::exit(1)
+                }
+            }
+        };
+
+        shell.verbose(|shell| {
+            shell.status_with_color(
+                "Exporting",
+                format!("CARGO_NDK_OUTPUT_PATH={output_dir:?}"),
+                termcolor::Color::Cyan,
+            )
+        })?;
+
+        std::env::set_var("CARGO_NDK_OUTPUT_PATH", output_dir);
+    }
+
+    shell.verbose(|shell| {
+        shell.status_with_color(
+            "Setting",
+            if platforms.by_target.is_empty() {
+                format!("Android SDK platform level to {}", platforms.default)
+            } else {
+                let levels = platforms
+                    .by_target
+                    .iter()
+                    .map(|(target, level)| format!("{level} for {target}"))
+                    .collect::<Vec<_>>();
+                format!(
+                    "Android SDK platform level to {} ({})",
+                    platforms.default,
+                    levels.join(", ")
+                )
+            },
+            termcolor::Color::Cyan,
+        )
+    })?;
+
+    shell.verbose(|shell| {
+        shell.status_with_color(
+            "Building",
+            format!(
+                "targets ({})",
+                targets
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            termcolor::Color::Cyan,
+        )
+    })?;
+
+    let start_time = Instant::now();
+
+    // Collects the outcome of each binary that `cargo-ndk-runner` runs on the device.
+    let report_path = out_dir.as_std_path().join("cargo-ndk-runner-report");
+    let _ = fs::remove_file(&report_path);
+    env::set_var(crate::runner::REPORT_ENV, &report_path);
+    if args.dry_run {
+        env::set_var(crate::runner::DRY_RUN_ENV, "1");
+    }
+    if args.quiet_adb || shell.verbosity() == Verbosity::Quiet {
+        env::set_var(crate::runner::QUIET_ADB_ENV, "1");
+    }
+    if let Some(dir) = args.device_dir.as_ref() {
+        env::set_var(crate::runner::DEVICE_DIR_ENV, dir);
+    }
+    if let Some(class) = args.via_app_process.as_ref() {
+        env::set_var(crate::runner::APP_PROCESS_CLASS_ENV, class);
+        if let Some(jar) = args.app_process_classpath.as_ref() {
+            // The runner is run by cargo from another directory
+            let jar = dunce::canonicalize(jar)
+                .with_context(|| format!("failed to find --app-process-classpath {jar:?}"))?;
+            env::set_var(crate::runner::APP_PROCESS_CLASSPATH_ENV, jar);
+        }
+    } else if args.app_process_classpath.is_some() {
+        shell.warn("--app-process-classpath has no effect without --via-app-process")?;
+    }
+    if let Some(package) = args.run_as.as_ref() {
+        env::set_var(crate::runner::RUN_AS_ENV, package);
+    }
+    if let Some(prefix) = args.device_shell_prefix.as_ref() {
+        env::set_var(crate::runner::SHELL_PREFIX_ENV, prefix);
+    }
+    // adb reads this itself, so it applies to every adb command, including the runner's
+    if let Some(serial) = args.adb_serial.as_ref() {
+        env::set_var("ANDROID_SERIAL", serial);
+    }
+    if args.dry_run && runs_binaries {
+        match crate::runner::device_serial()? {
+            Ok(serial) => shell.status("Would use", format!("device {serial}"))?,
+            Err(e) => shell.warn(format!("adb couldn't pick a device to run on: {e}"))?,
+        }
+        shell.status(
+            "Would use",
+            format!("{} on the device", crate::runner::device_dir()),
+        )?;
+    } else if (args.push_all || args.smoke_test)
+        && !runs_binaries
+        && env::var_os("ANDROID_SERIAL").is_none()
+    {
+        // Without a serial, adb uses whichever device is connected, so say which one that is.
+        // The runner says so itself for the binaries it runs.
+        if let Some(device) = crate::runner::describe_device() {
+            shell.status("Using", format!("device {device}"))?;
+        }
+    }
+
+    let mut runs = Vec::new();
+    let mut build_times = Vec::new();
+    let deadline = args
+        .build_timeout
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+
+    let targets = targets
+        .into_iter()
+        .map(|target| {
+            let triple = target.triple();
+            shell.status("Building", format!("{} ({})", &target, &triple))?;
+            let (ndk_home, ndk_version) = ndks.get(&target);
+
+            let build_start = Instant::now();
+            let (status, artifacts) = crate::cargo::run(
+                &mut shell,
+                &cargo_bin,
+                &working_dir,
+                ndk_home,
+                ndk_version,
+                triple,
+                platforms.get(&target),
+                &args.cargo_args,
+                &cargo_manifest,
+                args.bindgen,
+                args.legacy_toolchain,
+                !args.no_pkg_config_setup,
+                openssl_dir(args.openssl_sysroot.as_deref(), &target).as_deref(),
+                !args.no_linker_override,
+                &args.link_lib,
+                &target_cpu,
+                &extra_env,
+                args.print_cargo_cmd,
+                args.stdout_json_lines,
+                args.verbose_cc,
+                args.passthrough_output,
+                deadline,
+                &out_dir,
+            )?;
+            let code = crate::process::exit_code(&status);
+            build_times.push(build_start.elapsed());
+
+            let target_runs = crate::runner::take_report(&report_path)?;
+            let ran_binaries = !target_runs.is_empty();
+            runs.extend(target_runs.into_iter().map(|x| (target.clone(), x)));
+
+            // An interrupted build exits the way a shell reports a signal, without the hints
+            if let Some(signal) = crate::process::killed_by(&status) {
+                finish_runs(&mut shell, &runs, junit_path.as_deref())?;
+                std::process::exit(128 + signal);
+            }
+            if code != 0 {
+                finish_runs(&mut shell, &runs, junit_path.as_deref())?;
+                if !ran_binaries {
+                    shell.note(
+                        "If the build failed due to a missing target, you can run this command:",
+                    )?;
+                    shell.note("")?;
+                    shell.note(format!("    rustup target install {}", triple))?;
+                }
+                std::process::exit(code);
+            }
+
+            Ok((target, artifacts))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    if args.print_cargo_cmd {
+        return Ok(());
+    }
+
+    finish_runs(&mut shell, &runs, junit_path.as_deref())?;
+
+    // cargo only lists the test binaries it built in its human readable output, which it's given
+    // when that's passed through
+    if crate::cargo::only_builds_tests(&args.cargo_args) && !args.passthrough_output {
+        for (_, artifacts) in targets.iter() {
+            for artifact in artifacts {
+                if let Some(executable) = &artifact.executable {
+                    shell.status(
+                        "Executable",
+                        format!("{} ({executable})", artifact.target.name),
+                    )?;
+                }
+            }
+        }
+    }
+
+    if args.check_jni || !args.jni_class.is_empty() {
+        for (target, artifacts) in targets.iter() {
+            for file in artifacts
+                .iter()
+                .filter(|a| artifact_is_cdylib(a))
+                .flat_map(|a| a.filenames.iter())
+                .filter(|name| name.extension() == Some("so"))
+            {
+                match crate::cargo::exported_symbols(
+                    ndks.home(target),
+                    target.triple(),
+                    args.legacy_toolchain,
+                    file.as_std_path(),
+                ) {
+                    Some(exports) => {
+                        crate::jni::check_exports(&mut shell, file, &exports, &args.jni_class)?
+                    }
+                    None => shell.warn(format!(
+                        "couldn't read the symbols of {file}, so it wasn't checked for JNI symbols"
+                    ))?,
+                }
+            }
+        }
+    }
+
+    let mut sizes = Vec::new();
+    let mut symbols = Vec::new();
+    let mut pushes = Vec::new();
+
+    if let Some(output_dir) = args.output_dir.as_ref().filter(|_| output_dir_missing) {
+        shell.warn(format!(
+            "output dir {} could not be created, libraries were not copied",
+            output_dir.display()
+        ))?;
+
+        for (target, artifacts) in targets.iter() {
+            let arch_output_dir = output_dir.join(target.to_string());
+            for file in artifacts.iter().flat_map(|a| collected_files(a, &collect)) {
+                let dest = arch_output_dir.join(file.file_name().unwrap());
+                shell.note(format!("{file} -> {}", dest.display()))?;
+            }
+        }
+    } else if let Some(output_dir) = args.output_dir.as_ref() {
+        shell.concise(|shell| {
+            shell.status(
+                "Copying",
+                format!(
+                    "libraries to {}",
+                    dunce::canonicalize(output_dir).unwrap().display()
+                ),
+            )
+        })?;
+
+        for (target, artifacts) in targets.iter() {
+            shell.very_verbose(|shell| {
+                shell.note(format!("artifacts for {target}: {artifacts:?}"))
+            })?;
+
+            let arch_output_dir = output_dir.join(target.to_string());
+            fs::create_dir_all(&arch_output_dir).unwrap();
+            // Held until this ABI's libraries are copied and stripped
+            let _lock = if args.output_lock {
+                Some(OutputLock::acquire(&mut shell, output_dir, target)?)
+            } else {
+                None
+            };
 
-    let mut shell = Shell::new();
-    shell.set_verbosity(verbosity);
-    shell.set_color_choice(color)?;
+            let mut unusable = requested_packages
+                .iter()
+                .filter(|package| {
+                    !artifacts.iter().any(|a| {
+                        a.package_id == package.id && !collected_files(a, &collect).is_empty()
+                    })
+                })
+                .map(|package| {
+                    format!("No usable artifacts produced by cargo for {}", package.name)
+                })
+                .collect::<Vec<_>>();
+            if unusable.is_empty()
+                && artifacts
+                    .iter()
+                    .all(|a| collected_files(a, &collect).is_empty())
+            {
+                unusable.push("No usable artifacts produced by cargo".to_string());
+            }
+            if !unusable.is_empty() {
+                if args.ignore_no_cdylib {
+                    for message in unusable {
+                        shell.warn(format!("{message} ({target})"))?;
+                    }
+                } else {
+                    for message in unusable {
+                        shell.error(message)?;
+                    }
+                    if collect.contains(&ArtifactKind::Cdylib) {
+                        shell.error(
+                            "Did you set the crate-type in Cargo.toml to include 'cdylib'?",
+                        )?;
+                        shell.error("For more info, see <https://doc.rust-lang.org/cargo/reference/cargo-targets.html#library>.")?;
+                    }
+                    shell
+                        .note("pass --ignore-no-cdylib to copy whatever libraries were produced")?;
+                    std::process::exit(1);
+                }
+            }
 
-    let args = match ArgsEnv::parse_args(&args, gumdrop::ParsingStyle::StopAtFirstFree) {
-        Ok(args) if args.help => {
-            print_usage();
-            std::process::exit(0);
-        }
-        Ok(args) if args.version => {
-            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
-            std::process::exit(0);
-        }
-        Ok(args) => args,
-        Err(e) => {
-            shell.error(e)?;
-            std::process::exit(2);
-        }
-    };
+            let mut hashes = args
+                .copy_if_changed
+                .then(|| SourceHashes::load(output_dir, target));
 
-    let (ndk_home, _ndk_detection_method) = match derive_ndk_path(&mut shell) {
-        Some((path, method)) => (path, method),
-        None => {
-            shell.error("Could not find any NDK.")?;
-            shell.note(
-                "Set the environment ANDROID_NDK_HOME to your NDK installation's root directory,\nor install the NDK using Android Studio."
-            )?;
-            std::process::exit(1);
-        }
-    };
+            let arch_debug_dir = match args.debug_dir.as_ref() {
+                Some(debug_dir) => debug_dir.join(target.to_string()),
+                None => arch_output_dir.clone(),
+            };
+            if args.split_debug_info && !args.no_strip {
+                fs::create_dir_all(&arch_debug_dir)
+                    .with_context(|| format!("failed to create {arch_debug_dir:?}"))?;
+            }
 
-    let clang_target = clang_target(
-        args.target.triple(),
-        args.platform.unwrap_or(Ndk::default().platform),
-    );
+            for file in artifacts.iter().flat_map(|a| collected_files(a, &collect)) {
+                let dest = arch_output_dir.join(file.file_name().unwrap());
+                if args.push_all && file.extension() != Some("so") {
+                    pushes.push((target.clone(), dest.clone()));
+                }
 
-    // Try command line, then config. Config falls back to defaults in any case.
-    let env = build_env(args.target.triple(), &ndk_home, &clang_target, args.bindgen)
-        .into_iter()
-        .filter(|(k, _)| !k.starts_with('_'))
-        .collect::<BTreeMap<_, _>>();
+                if let Some(symbols_dir) = args.symbols_dir.as_ref() {
+                    symbols.push(copy_symbols(
+                        &mut shell,
+                        ndks.home(target),
+                        target,
+                        args.legacy_toolchain,
+                        file,
+                        symbols_dir,
+                    )?);
+                }
 
-    if args.json {
-        println!(
-            "{}",
-            serde_json::to_string_pretty(
-                &env.into_iter()
-                    .map(|(k, v)| (k, v.to_str().unwrap().to_string()))
-                    .collect::<BTreeMap<_, _>>()
-            )
-            .unwrap()
-        );
-    } else if args.powershell {
-        for (k, v) in env {
-            println!("${{env:{}}}={:?}", k, v);
-        }
-        println!();
-        println!("# To import with PowerShell:");
-        println!("#     cargo ndk-env --powershell | Out-String | Invoke-Expression");
-    } else {
-        for (k, v) in env {
-            println!("export {}={:?}", k.to_uppercase().replace('-', "_"), v);
-        }
-        println!();
-        println!("# To import with bash/zsh/etc:");
-        println!("#     source <(cargo ndk-env)");
-    }
+                let name = file.file_name().unwrap();
+                let source_hash = match hashes {
+                    Some(_) => Some(crate::source_hashes::sha256(file.as_std_path())?),
+                    None => None,
+                };
+                let fresh = match (&hashes, &source_hash) {
+                    (Some(hashes), Some(hash)) => {
+                        hashes.is_fresh(name, hash, &dest, !args.no_strip)?
+                    }
+                    _ => is_fresh(file.as_std_path(), &dest)?,
+                };
 
-    Ok(())
-}
+                if fresh {
+                    shell.status("Fresh", file)?;
+                    if let Some(compression) = args.compress {
+                        if !is_fresh(&dest, &compression.sidecar(&dest))? {
+                            compress(&mut shell, compression, &dest)?;
+                        }
+                    }
+                    sizes.push(LibrarySize::from_path(target, &dest)?);
+                    continue;
+                }
 
-pub fn run(args: Vec<String>) -> anyhow::Result<()> {
-    if args.is_empty() || args.contains(&"-h".into()) || args.contains(&"--help".into()) {
-        print_usage();
-        std::process::exit(0);
-    }
+                shell.verbose(|shell| {
+                    shell.status("Copying", format!("{file} -> {}", &dest.display()))
+                })?;
 
-    let verbosity = if args.contains(&"-q".into()) {
-        Verbosity::Quiet
-    } else if args.contains(&"-vv".into()) {
-        Verbosity::VeryVerbose
-    } else if args.contains(&"-v".into()) || args.contains(&"--verbose".into()) {
-        Verbosity::Verbose
-    } else {
-        Verbosity::Normal
-    };
+                // Readers of the output directory, such as Gradle, only ever see the stripped
+                // library in full
+                replace_file(&dest, |tmp| {
+                    fs::copy(file, tmp)
+                        .with_context(|| format!("failed to copy {file:?} over to {tmp:?}"))?;
+
+                    filetime::set_file_mtime(
+                        tmp,
+                        FileTime::from_last_modification_time(
+                            &tmp.metadata()
+                                .with_context(|| format!("failed getting metadata for {tmp:?}"))?,
+                        ),
+                    )
+                    .with_context(|| {
+                        format!("unable to update the modification time of {tmp:?}")
+                    })?;
 
-    let color = args
-        .iter()
-        .position(|x| x == "--color")
-        .and_then(|p| args.get(p + 1))
-        .map(|x| &**x);
+                    if !args.no_strip {
+                        // Taken out before stripping, then linked to from the stripped library
+                        let debug_file = args.split_debug_info.then(|| {
+                            let mut name = dest.file_name().unwrap().to_os_string();
+                            name.push(".debug");
+                            arch_debug_dir.join(name)
+                        });
+                        if let Some(debug_file) = debug_file.as_ref() {
+                            shell.verbose(|shell| {
+                                shell.status("Splitting", format!("{}", debug_file.display()))
+                            })?;
+                            crate::cargo::only_keep_debug(
+                                ndks.home(target),
+                                target.triple(),
+                                args.legacy_toolchain,
+                                tmp,
+                                debug_file,
+                            )
+                            .with_context(|| {
+                                format!("failed to split the debug info of {}", dest.display())
+                            })?;
+                        }
+
+                        shell.verbose(|shell| {
+                            shell.status("Stripping", format!("{}", dest.display()))
+                        })?;
+                        crate::cargo::strip(
+                            ndks.home(target),
+                            target.triple(),
+                            args.legacy_toolchain,
+                            strip_mode,
+                            tmp,
+                        )
+                        .with_context(|| format!("failed to strip {}", dest.display()))?;
+
+                        if let Some(debug_file) = debug_file.as_ref() {
+                            crate::cargo::add_gnu_debuglink(
+                                ndks.home(target),
+                                target.triple(),
+                                args.legacy_toolchain,
+                                tmp,
+                                debug_file,
+                            )
+                            .with_context(|| {
+                                format!("failed to link {} to its debug info", dest.display())
+                            })?;
+                        }
+                    }
+
+                    Ok(())
+                })?;
 
-    let mut shell = Shell::new();
-    shell.set_verbosity(verbosity);
-    shell.set_color_choice(color)?;
+                if let (Some(hashes), Some(hash)) = (hashes.as_mut(), source_hash) {
+                    hashes.insert(name, hash);
+                }
 
-    if std::env::var_os("CARGO_NDK_NO_PANIC_HOOK").is_none() {
-        panic::set_hook(Box::new(panic_hook));
-    }
+                if let Some(compression) = args.compress {
+                    compress(&mut shell, compression, &dest)?;
+                }
 
-    if !is_supported_rustc_version() {
-        shell.error("Rust compiler is too old and not supported by cargo-ndk.")?;
-        shell.note("Upgrade Rust to at least v1.68.0.")?;
-        std::process::exit(1);
-    }
+                sizes.push(LibrarySize::from_path(target, &dest)?);
+            }
 
-    let build_mode = if args.contains(&"--release".into()) {
-        BuildMode::Release
-    } else if let Some(i) = args.iter().position(|x| x == "--profile") {
-        args.get(i + 1)
-            .map(|p| BuildMode::from(p.as_str()))
-            .unwrap_or(BuildMode::Debug)
-    } else {
-        args.iter()
-            .find_map(|a| a.strip_prefix("--profile=").map(BuildMode::from))
-            .unwrap_or(BuildMode::Debug)
-    };
+            if let Some(hashes) = hashes {
+                hashes.save()?;
+            }
+        }
 
-    let args = match Args::parse_args(&args, gumdrop::ParsingStyle::StopAtFirstFree) {
-        Ok(args) if args.help => {
-            print_usage();
-            std::process::exit(0);
+        crate::size::print_summary(&mut shell, &sizes)?;
+
+        if let Some(path) = args.size_baseline.as_ref() {
+            let baseline = SizeManifest::read(path)?;
+            if crate::size::print_diff(&mut shell, &sizes, &baseline, args.size_threshold)? {
+                std::process::exit(1);
+            }
         }
-        Ok(args) if args.version => {
-            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
-            std::process::exit(0);
+
+        if let Some(path) = args.size_manifest.as_ref() {
+            SizeManifest {
+                libraries: sizes.clone(),
+            }
+            .write(path)?;
         }
-        Ok(args) => args,
-        Err(e) => {
-            shell.error(e)?;
-            std::process::exit(2);
+
+        if let Some(symbols_dir) = args.symbols_dir.as_ref() {
+            SymbolIndex { libraries: symbols }.write(symbols_dir)?;
         }
-    };
 
-    if args.cargo_args.is_empty() {
-        shell.error("No args found to pass to cargo!")?;
-        shell.note("You still need to specify build arguments to cargo to achieve anything. :)")?;
-        std::process::exit(1);
+        if args.push_all {
+            if pushes.is_empty() {
+                shell.warn(
+                    "--push-all found no binaries or examples to push; select them with --collect",
+                )?;
+            }
+            let quiet = args.quiet_adb || shell.verbosity() == Verbosity::Quiet;
+            crate::runner::push_all(&mut shell, &pushes, quiet)?;
+        }
     }
 
-    let metadata = match MetadataCommand::new().no_deps().exec() {
-        Ok(v) => v,
-        Err(e) => {
-            shell.error("Failed to load Cargo.toml in current directory.")?;
-            shell.error(e)?;
-            std::process::exit(1);
-        }
-    };
+    if args.smoke_test {
+        let copied = args.output_dir.is_some() && !output_dir_missing;
+        let abis = crate::smoke_test::device_abis()?;
+        let quiet = args.quiet_adb || shell.verbosity() == Verbosity::Quiet;
+        let mut failed = false;
+        for (target, artifacts) in targets.iter() {
+            if !abis.contains(&target.to_string()) {
+                shell.warn(format!(
+                    "the device can't load {target} libraries, so they weren't smoke tested"
+                ))?;
+                continue;
+            }
 
-    let out_dir = metadata.target_directory;
+            let built = artifacts
+                .iter()
+                .flat_map(|a| collected_files(a, &collect))
+                .filter(|file| file.extension() == Some("so"))
+                .collect::<Vec<_>>();
+            // The copies in the output directory are what gets packaged, beside whatever else
+            // was put there, such as libc++_shared.so
+            let (libraries, dependencies) = match args.output_dir.as_ref().filter(|_| copied) {
+                Some(output_dir) => {
+                    let arch_output_dir = output_dir.join(target.to_string());
+                    let libraries = built
+                        .iter()
+                        .map(|file| arch_output_dir.join(file.file_name().unwrap()))
+                        .collect::<Vec<_>>();
+                    let dependencies = fs::read_dir(&arch_output_dir)
+                        .with_context(|| format!("failed to read {arch_output_dir:?}"))?
+                        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                        .filter(|path| path.extension().is_some_and(|ext| ext == "so"))
+                        .filter(|path| !libraries.contains(path))
+                        .collect();
+                    (libraries, dependencies)
+                }
+                None => (
+                    built
+                        .iter()
+                        .map(|file| file.as_std_path().to_path_buf())
+                        .collect(),
+                    Vec::new(),
+                ),
+            };
+            if libraries.is_empty() {
+                continue;
+            }
 
-    // We used to check for NDK_HOME, so we'll keep doing that. But we'll also try ANDROID_NDK_HOME
-    // and $ANDROID_SDK_HOME/ndk as this is how Android Studio configures the world
-    let (ndk_home, ndk_detection_method) = match derive_ndk_path(&mut shell) {
-        Some((path, method)) => (path, method),
-        None => {
-            shell.error("Could not find any NDK.")?;
-            shell.note(
-                "Set the environment ANDROID_NDK_HOME to your NDK installation's root directory,\nor install the NDK using Android Studio."
-            )?;
-            std::process::exit(1);
+            shell.status("Smoke testing", format!("{target} libraries on the device"))?;
+            let work_dir = out_dir
+                .as_std_path()
+                .join("cargo-ndk")
+                .join("smoke-test")
+                .join(target.to_string());
+            if !crate::smoke_test::check(
+                &mut shell,
+                ndks.home(target),
+                target,
+                platforms.get(target),
+                &work_dir,
+                &libraries,
+                &dependencies,
+                quiet,
+            )? {
+                failed = true;
+            }
         }
-    };
-
-    let ndk_version = match derive_ndk_version(&ndk_home) {
-        Ok(v) => v,
-        Err(e) => {
-            shell.error(format!(
-                "Error detecting NDK version for path {}",
-                ndk_home.display()
-            ))?;
-            shell.error(e)?;
+        if failed {
             std::process::exit(1);
         }
-    };
+    }
+
+    if let Some(path) = args.timings_json.as_ref() {
+        let copied = args.output_dir.is_some() && !output_dir_missing;
+        let timings = targets
+            .iter()
+            .zip(&build_times)
+            .map(|((target, artifacts), elapsed)| {
+                let files = artifacts
+                    .iter()
+                    .flat_map(|a| collected_files(a, &collect))
+                    .collect::<Vec<_>>();
+                // The size of the copies in the output directory, as stripped, if there are any
+                let bytes = if copied {
+                    sizes
+                        .iter()
+                        .filter(|size| size.abi == target.to_string())
+                        .map(|size| size.bytes)
+                        .sum()
+                } else {
+                    files
+                        .iter()
+                        .filter_map(|file| file.metadata().ok())
+                        .map(|metadata| metadata.len())
+                        .sum()
+                };
+                TargetTiming {
+                    abi: target.to_string(),
+                    triple: target.triple().to_string(),
+                    secs: elapsed.as_secs_f64(),
+                    artifacts: files.len(),
+                    bytes,
+                }
+            })
+            .collect();
+        TimingsReport::new(start_time.elapsed(), timings).write(path)?;
+        shell.verbose(|shell| shell.status("Wrote", format!("timings to {}", path.display())))?;
+    }
+
+    // The libraries where cargo built them, for scripts that would otherwise have to work out
+    // cargo's target directory layout
+    if args.print_artifacts {
+        let built = targets
+            .iter()
+            .map(|(target, artifacts)| {
+                let files = artifacts
+                    .iter()
+                    .flat_map(|a| collected_files(a, &collect))
+                    .collect::<Vec<_>>();
+                (target.to_string(), files)
+            })
+            .collect::<BTreeMap<_, _>>();
+        println!("{}", serde_json::to_string_pretty(&built)?);
+    }
 
     shell.verbose(|shell| {
-        shell.status_with_color(
-            "Detected",
-            format!(
-                "NDK v{} ({}) [{}]",
-                ndk_version,
-                ndk_home.display(),
-                ndk_detection_method
-            ),
-            termcolor::Color::Cyan,
-        )
+        let duration = start_time.elapsed();
+        let secs = duration.as_secs();
+        let d = if secs >= 60 {
+            format!("{}m {:02}s", secs / 60, secs % 60)
+        } else {
+            format!("{}.{:02}s", secs, duration.subsec_nanos() / 10_000_000)
+        };
+        let t = targets
+            .iter()
+            .map(|(target, _)| target.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        shell.status("Finished", format!("targets ({t}) in {d}",))
     })?;
 
-    let working_dir = env::current_dir().expect("current directory could not be resolved");
+    Ok(())
+}
 
-    // Attempt to smartly determine exactly what package is being worked with. The following is the manifest priority:
-    //
-    // 1. --manifest-path in the command-line arguments
-    // 2. The manifest path of the package specified with `-p` for cargo.
-    // 3. The manifest path in the current working dir
-    let cargo_args = &args.cargo_args;
-    let cargo_manifest = args
-        .manifest_path
-        .or_else(|| {
-            if let Some(selected_package) = cargo_args
-                .iter()
-                .position(|arg| arg == "-p" || arg == "--package")
-                .and_then(|idx| cargo_args.get(idx + 1))
-            {
-                let selected_package = metadata
-                    .packages
-                    .iter()
-                    .find(|p| &p.name == selected_package)
-                    .unwrap_or_else(|| panic!("unknown package: {selected_package}"));
+/// Summarise the binaries that were run on the device, and write the test report if requested.
+fn finish_runs(
+    shell: &mut Shell,
+    runs: &[(Target, RunResult)],
+    junit_path: Option<&Path>,
+) -> anyhow::Result<()> {
+    if runs.len() > 1 {
+        crate::runner::print_summary(shell, runs)?;
+    }
 
-                Some(selected_package.manifest_path.as_std_path().to_path_buf())
-            } else {
-                None
-            }
-        })
-        .unwrap_or_else(|| working_dir.join("Cargo.toml"));
+    if let Some(path) = junit_path {
+        crate::junit::write(path, runs)?;
+        shell.status("Wrote", format!("test report to {}", path.display()))?;
+    }
 
-    let config = match crate::meta::config(&cargo_manifest, &build_mode) {
-        Ok(v) => v,
-        Err(e) => {
-            shell.error("Failed loading manifest")?;
-            shell.error(e)?;
-            std::process::exit(1);
-        }
+    Ok(())
+}
+
+/// Resolve the OpenSSL install to use for `target`, preferring a per-ABI subdirectory.
+fn openssl_dir(openssl_sysroot: Option<&Path>, target: &Target) -> Option<PathBuf> {
+    let openssl_sysroot = openssl_sysroot?;
+    let abi_dir = openssl_sysroot.join(target.to_string());
+    let dir = if abi_dir.is_dir() {
+        abi_dir
+    } else {
+        openssl_sysroot.to_path_buf()
     };
 
-    let cmake_toolchain_path = ndk_home
-        .join("build")
-        .join("cmake")
-        .join("android.toolchain.cmake");
+    // openssl-sys may run from a different current_dir, so make sure the path is absolute.
+    Some(dunce::canonicalize(&dir).unwrap_or(dir))
+}
+
+/// Print the C compiler environment configured for `target` and a sample compile command, so
+/// that failing C dependency builds can be reproduced by hand.
+fn dump_clang_cmdline(
+    target: &Target,
+    ndk_home: &Path,
+    platform: u8,
+    bindgen: bool,
+    legacy_toolchain: bool,
+    target_cpu: &TargetCpu,
+) {
+    let triple = target.triple();
+    let env = build_env(
+        triple,
+        ndk_home,
+        platform,
+        bindgen,
+        legacy_toolchain,
+        true,
+        None,
+        true,
+        &[],
+        target_cpu,
+    );
 
-    shell.very_verbose(|shell| {
-        shell.status_with_color(
-            "Exporting",
-            format!("CARGO_NDK_CMAKE_TOOLCHAIN_PATH={:?}", &cmake_toolchain_path),
-            termcolor::Color::Cyan,
-        )
-    })?;
-    env::set_var("CARGO_NDK_CMAKE_TOOLCHAIN_PATH", cmake_toolchain_path);
+    println!("# {target} ({triple})");
+    for base in ["CC", "CFLAGS", "CXX", "CXXFLAGS", "AR"] {
+        let (key, _) = cc_env(base, triple);
+        if let Some(value) = env.get(&key) {
+            println!("{key}={value:?}");
+        }
+    }
+    let sysroot = &env["CARGO_NDK_SYSROOT_PATH"];
+    println!("CARGO_NDK_SYSROOT_PATH={sysroot:?}");
 
-    let platform = args.platform.unwrap_or(config.platform);
+    let (cc_key, _) = cc_env("CC", triple);
+    let (cflags_key, _) = cc_env("CFLAGS", triple);
+    println!(
+        "{:?} {} --sysroot={:?} -c foo.c -o foo.o",
+        env[&cc_key],
+        env[&cflags_key].to_string_lossy(),
+        sysroot
+    );
+    println!();
+}
 
-    // Try command line, then config. Config falls back to defaults in any case.
-    let targets = if !args.target.is_empty() {
-        args.target
-    } else {
-        config.targets
-    };
+#[derive(Serialize)]
+struct EnvChange {
+    before: Option<String>,
+    after: String,
+}
 
-    if let Some(output_dir) = args.output_dir.as_ref() {
-        if let Err(e) = fs::create_dir_all(output_dir) {
-            shell.error(format!("failed to create output dir, {e}"))?;
-            std::process::exit(1);
+#[derive(Serialize)]
+struct EnvDiff {
+    abi: String,
+    triple: String,
+    changes: BTreeMap<String, EnvChange>,
+}
+
+/// Print the environment variables that the build of each target would add to or change in the
+/// current environment, with their values before and after.
+#[allow(clippy::too_many_arguments)]
+fn print_env_diff(
+    targets: &[Target],
+    ndks: &Ndks,
+    platforms: &Platforms,
+    bindgen: bool,
+    legacy_toolchain: bool,
+    pkg_config: bool,
+    openssl_sysroot: Option<&Path>,
+    linker_override: bool,
+    link_libs: &[String],
+    target_cpu: &TargetCpu,
+    extra_env: &[(String, String)],
+    json: bool,
+) -> anyhow::Result<()> {
+    let mut all = Vec::new();
+    for target in targets {
+        let triple = target.triple();
+        let mut env = build_env(
+            triple,
+            ndks.home(target),
+            platforms.get(target),
+            bindgen,
+            legacy_toolchain,
+            pkg_config,
+            openssl_dir(openssl_sysroot, target).as_deref(),
+            linker_override,
+            link_libs,
+            target_cpu,
+        );
+        // `--env` and `--env-file` are applied over cargo-ndk's own
+        for (key, value) in extra_env {
+            env.insert(key.clone(), value.into());
         }
 
-        // Canonicalize because path is shared with build scripts that can run in a different current_dir.
-        let output_dir = match dunce::canonicalize(output_dir) {
-            Ok(p) => p,
-            Err(e) => {
-                shell.error(format!("failed to canonicalize output dir, {e}"))?;
-                if out_dir.is_absolute() {
-                    output_dir.clone()
-                } else {
-                    std::process::exit(1)
+        let changes = env
+            .into_iter()
+            .filter_map(|(key, after)| {
+                let before = env::var_os(&key);
+                if before.as_ref() == Some(&after) {
+                    return None;
+                }
+                let change = EnvChange {
+                    before: before.map(|x| x.to_string_lossy().into_owned()),
+                    after: after.to_string_lossy().into_owned(),
+                };
+                Some((key, change))
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        if !json {
+            println!("# {target} ({triple})");
+            for (key, change) in &changes {
+                match &change.before {
+                    Some(before) => println!("{key}={:?} (was {before:?})", change.after),
+                    None => println!("{key}={:?}", change.after),
                 }
             }
-        };
+            println!();
+        }
 
-        shell.verbose(|shell| {
-            shell.status_with_color(
-                "Exporting",
-                format!("CARGO_NDK_OUTPUT_PATH={output_dir:?}"),
-                termcolor::Color::Cyan,
-            )
-        })?;
+        all.push(EnvDiff {
+            abi: target.to_string(),
+            triple: triple.to_string(),
+            changes,
+        });
+    }
 
-        std::env::set_var("CARGO_NDK_OUTPUT_PATH", output_dir);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&all)?);
     }
 
-    shell.verbose(|shell| {
-        shell.status_with_color(
-            "Setting",
-            format!("Android SDK platform level to {}", platform),
-            termcolor::Color::Cyan,
-        )
-    })?;
+    Ok(())
+}
 
-    env::set_var("CARGO_NDK_ANDROID_PLATFORM", platform.to_string());
-    shell.verbose(|shell| {
-        shell.status_with_color(
-            "Building",
-            format!(
-                "targets ({})",
-                targets
-                    .iter()
-                    .map(ToString::to_string)
-                    .collect::<Vec<_>>()
-                    .join(", ")
-            ),
-            termcolor::Color::Cyan,
-        )
-    })?;
+#[derive(Serialize)]
+struct SysrootLibs {
+    abi: String,
+    triple: String,
+    platform: u8,
+    libraries: Vec<PathBuf>,
+}
 
-    let start_time = Instant::now();
+/// The directories in the NDK's sysroot with the libraries that can be linked against for
+/// `target`: the one for the platform level, followed by the one for every level, which has the
+/// likes of the C++ runtime.
+fn sysroot_lib_dirs(
+    target: &Target,
+    ndk_home: &Path,
+    platform: u8,
+    legacy_toolchain: bool,
+) -> [PathBuf; 2] {
+    let triple = target.triple();
+    let env = build_env(
+        triple,
+        ndk_home,
+        platform,
+        false,
+        legacy_toolchain,
+        true,
+        None,
+        true,
+        &[],
+        &TargetCpu::default(),
+    );
+    let libs_path = PathBuf::from(&env["CARGO_NDK_SYSROOT_LIBS_PATH"]);
+    [libs_path.join(platform.to_string()), libs_path]
+}
 
-    let targets = targets
+/// The `.so` and `.a` files in `dir`, sorted.
+fn linkable_files(dir: &Path) -> Vec<PathBuf> {
+    let mut paths = fs::read_dir(dir)
         .into_iter()
-        .map(|target| {
-            let triple = target.triple();
-            shell.status("Building", format!("{} ({})", &target, &triple))?;
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .is_some_and(|ext| ext == "so" || ext == "a")
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+}
 
-            shell.very_verbose(|shell| {
-                shell.status_with_color(
-                    "Exporting",
-                    format!("CARGO_NDK_ANDROID_PLATFORM={:?}", &target.to_string()),
-                    termcolor::Color::Cyan,
-                )
-            })?;
-            env::set_var("CARGO_NDK_ANDROID_PLATFORM", target.to_string());
+/// The targets the NDK has no sysroot for at their platform level, each with the platform levels
+/// it does have for that target, sorted, which is empty if it doesn't support the target at all.
+fn unsupported_targets(
+    targets: &[Target],
+    ndks: &Ndks,
+    platforms: &Platforms,
+    legacy_toolchain: bool,
+) -> Vec<(Target, Vec<u8>)> {
+    let mut unsupported = Vec::new();
+    for target in targets {
+        let platform = platforms.get(target);
+        let [platform_dir, libs_dir] =
+            sysroot_lib_dirs(target, ndks.home(target), platform, legacy_toolchain);
+        if platform_dir.is_dir() {
+            continue;
+        }
 
-            // Set ANDROID_PLATFORM (API level)
-            shell.very_verbose(|shell| {
-                shell.status_with_color(
-                    "Exporting",
-                    format!("ANDROID_PLATFORM={}", platform),
-                    termcolor::Color::Cyan,
-                )
-            })?;
-            env::set_var("ANDROID_PLATFORM", platform.to_string());
+        let mut levels = fs::read_dir(&libs_dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u8>().ok())
+            .collect::<Vec<_>>();
+        levels.sort();
+        unsupported.push((target.clone(), levels));
+    }
+    unsupported
+}
 
-            // Set ANDROID_ABI using the Android-specific target name
-            let android_abi = target.to_string();
-            shell.very_verbose(|shell| {
-                shell.status_with_color(
-                    "Exporting",
-                    format!("ANDROID_ABI={:?}", &android_abi),
-                    termcolor::Color::Cyan,
-                )
-            })?;
-            env::set_var("ANDROID_ABI", android_abi);
+/// List the libraries in the NDK's sysroot that can be linked against for each target.
+fn print_sysroot_libs(
+    shell: &mut Shell,
+    targets: &[Target],
+    ndks: &Ndks,
+    platforms: &Platforms,
+    legacy_toolchain: bool,
+    json: bool,
+) -> anyhow::Result<()> {
+    let mut all = Vec::new();
+    for target in targets {
+        let triple = target.triple();
+        let platform = platforms.get(target);
+        let dirs = sysroot_lib_dirs(target, ndks.home(target), platform, legacy_toolchain);
+        if !dirs[0].is_dir() {
+            shell.warn(format!(
+                "the NDK has no libraries for {target} at platform level {platform}, expected in {}",
+                dirs[0].display()
+            ))?;
+        }
 
-            let (status, artifacts) = crate::cargo::run(
-                &mut shell,
-                &working_dir,
-                &ndk_home,
-                &ndk_version,
-                triple,
-                platform,
-                &args.cargo_args,
-                &cargo_manifest,
-                args.bindgen,
-                &out_dir,
-            )?;
-            let code = status.code().unwrap_or(-1);
+        let libraries = dirs
+            .iter()
+            .flat_map(|dir| linkable_files(dir))
+            .collect::<Vec<_>>();
 
-            if code != 0 {
-                shell.note(
-                    "If the build failed due to a missing target, you can run this command:",
-                )?;
-                shell.note("")?;
-                shell.note(format!("    rustup target install {}", triple))?;
-                std::process::exit(code);
+        if !json {
+            println!("# {target} ({triple}), platform level {platform}");
+            for library in &libraries {
+                println!("{}", library.display());
             }
+            println!();
+        }
 
-            Ok((target, artifacts))
-        })
-        .collect::<anyhow::Result<Vec<_>>>()?;
+        all.push(SysrootLibs {
+            abi: target.to_string(),
+            triple: triple.to_string(),
+            platform,
+            libraries,
+        });
+    }
 
-    if let Some(output_dir) = args.output_dir.as_ref() {
-        shell.concise(|shell| {
-            shell.status(
-                "Copying",
-                format!(
-                    "libraries to {}",
-                    dunce::canonicalize(output_dir).unwrap().display()
-                ),
-            )
-        })?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&all)?);
+    }
 
-        for (target, artifacts) in targets.iter() {
-            shell.very_verbose(|shell| {
-                shell.note(format!("artifacts for {target}: {artifacts:?}"))
-            })?;
+    Ok(())
+}
 
-            let arch_output_dir = output_dir.join(target.to_string());
-            fs::create_dir_all(&arch_output_dir).unwrap();
+/// Warn about any `--link-lib` that isn't in the NDK's sysroot for a target, as it can only be
+/// found if it's elsewhere on the library search path, such as a directory added by a build script.
+fn check_link_libs(
+    shell: &mut Shell,
+    link_libs: &[String],
+    targets: &[Target],
+    ndks: &Ndks,
+    platforms: &Platforms,
+    legacy_toolchain: bool,
+) -> anyhow::Result<()> {
+    for target in targets {
+        let platform = platforms.get(target);
+        let libraries = sysroot_lib_dirs(target, ndks.home(target), platform, legacy_toolchain)
+            .iter()
+            .flat_map(|dir| linkable_files(dir))
+            .filter_map(|path| path.file_name().map(|x| x.to_string_lossy().to_string()))
+            .collect::<Vec<_>>();
 
-            if artifacts.is_empty() || !artifacts.iter().any(artifact_is_cdylib) {
-                shell.error("No usable artifacts produced by cargo")?;
-                shell.error("Did you set the crate-type in Cargo.toml to include 'cdylib'?")?;
-                shell.error("For more info, see <https://doc.rust-lang.org/cargo/reference/cargo-targets.html#library>.")?;
-                std::process::exit(1);
+        for name in link_libs {
+            let found = ["so", "a"]
+                .iter()
+                .any(|ext| libraries.contains(&format!("lib{name}.{ext}")));
+            if !found {
+                shell.warn(format!(
+                    "--link-lib {name}: lib{name} is not in the NDK's sysroot for {target} at platform level {platform}"
+                ))?;
+                shell.note("run with --print-sysroot-libs to see the libraries it has")?;
             }
+        }
+    }
 
-            for artifact in artifacts.iter().filter(|a| artifact_is_cdylib(a)) {
-                let Some(file) = artifact
-                    .filenames
-                    .iter()
-                    .find(|name| name.extension() == Some("so"))
-                else {
-                    // This should never happen because we filter for cdylib outputs above but you
-                    // never know... and it still feels better than just unwrapping
-                    shell.error("No cdylib file found to copy")?;
-                    std::process::exit(1);
-                };
+    Ok(())
+}
 
-                let dest = arch_output_dir.join(file.file_name().unwrap());
+/// Whether cargo is building the standard library from source, with `-Zbuild-std`, so it needn't be
+/// installed.
+fn builds_std(cargo_args: &[String]) -> bool {
+    let args = &cargo_args[..cargo_args
+        .iter()
+        .position(|x| x == "--")
+        .unwrap_or(cargo_args.len())];
+    env::var_os("CARGO_UNSTABLE_BUILD_STD").is_some()
+        || args.iter().any(|arg| arg.starts_with("-Zbuild-std"))
+        || args
+            .windows(2)
+            .any(|pair| pair[0] == "-Z" && pair[1].starts_with("build-std"))
+}
 
-                if is_fresh(file, &dest)? {
-                    shell.status("Fresh", file)?;
-                    continue;
-                }
+/// The triples that have no standard library in rustc's sysroot, or `None` if the sysroot can't be
+/// found, as with a rustc that doesn't keep targets there.
+fn missing_rust_targets<'a>(
+    triples: &[&'a str],
+    extra_env: &[(String, String)],
+) -> Option<Vec<&'a str>> {
+    let rustc = extra_env
+        .iter()
+        .rev()
+        .find(|(k, _)| k == "RUSTC")
+        .map(|(_, v)| OsString::from(v))
+        .or_else(|| env::var_os("RUSTC"))
+        .unwrap_or_else(|| "rustc".into());
+    let output = std::process::Command::new(rustc)
+        .args(["--print", "sysroot"])
+        .envs(extra_env.iter().cloned())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
 
-                shell.verbose(|shell| {
-                    shell.status("Copying", format!("{file} -> {}", &dest.display()))
-                })?;
+    let rustlib = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim())
+        .join("lib")
+        .join("rustlib");
+    if !rustlib.is_dir() {
+        return None;
+    }
 
-                fs::copy(file, &dest)
-                    .with_context(|| format!("failed to copy {file:?} over to {dest:?}"))?;
+    Some(
+        triples
+            .iter()
+            .filter(|triple| !rustlib.join(triple).join("lib").is_dir())
+            .copied()
+            .collect(),
+    )
+}
 
-                filetime::set_file_mtime(
-                    &dest,
-                    FileTime::from_last_modification_time(
-                        &dest
-                            .metadata()
-                            .with_context(|| format!("failed getting metadata for {dest:?}"))?,
-                    ),
-                )
-                .with_context(|| format!("unable to update the modification time of {dest:?}"))?;
+/// The name of the toolchain rustup uses here, as in `stable-x86_64-unknown-linux-gnu`.
+fn rustup_active_toolchain() -> anyhow::Result<String> {
+    let output = std::process::Command::new("rustup")
+        .args(["show", "active-toolchain"])
+        .output()
+        .context("failed to run rustup")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let message = stderr.lines().next().unwrap_or_default();
+        anyhow::bail!("{}", message.strip_prefix("error: ").unwrap_or(message));
+    }
 
-                if !args.no_strip {
-                    shell.verbose(|shell| {
-                        shell.status(
-                            "Stripping",
-                            format!("{}", &dunce::canonicalize(&dest).unwrap().display()),
-                        )
-                    })?;
-                    let _ = crate::cargo::strip(&ndk_home, &dest);
-                }
-            }
+    // The name may be followed by why it's active, as in "(default)"
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .map(str::to_string)
+        .context("rustup didn't name the active toolchain")
+}
+
+/// The host triple of the rustc in a rustup toolchain, if it's installed.
+fn rustc_host(toolchain: &str) -> Option<String> {
+    let output = std::process::Command::new("rustc")
+        .arg("-vV")
+        .env("RUSTUP_TOOLCHAIN", toolchain)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.strip_prefix("host: "))
+        .map(str::to_string)
+}
+
+/// Ask rustup where the cargo binary for `toolchain` is.
+fn rustup_which_cargo(toolchain: &str) -> anyhow::Result<PathBuf> {
+    let output = std::process::Command::new("rustup")
+        .args(["which", "cargo", "--toolchain", toolchain])
+        .output()
+        .context("failed to run rustup")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let message = stderr.lines().next().unwrap_or_default();
+        anyhow::bail!("{}", message.strip_prefix("error: ").unwrap_or(message));
+    }
+
+    Ok(PathBuf::from(
+        String::from_utf8_lossy(&output.stdout).trim().to_string(),
+    ))
+}
+
+fn is_executable(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        path.metadata()
+            .map(|x| x.is_file() && x.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    }
+
+    #[cfg(not(unix))]
+    {
+        path.is_file()
+    }
+}
+
+/// The packages that were asked for with cargo's `-p`, or the package at the manifest when none
+/// were, each of which is expected to produce a cdylib. Other workspace members may only be built
+/// as dependencies, so aren't expected to.
+///
+/// Empty when the packages can't be told apart, such as with `--workspace` or a virtual manifest.
+fn requested_packages<'a>(
+    cargo_args: &[String],
+    metadata: &'a Metadata,
+    cargo_manifest: &Path,
+) -> Vec<&'a Package> {
+    let cargo_args = cargo_args
+        .iter()
+        .take_while(|arg| *arg != "--")
+        .collect::<Vec<_>>();
+
+    if cargo_args
+        .iter()
+        .any(|arg| *arg == "--workspace" || *arg == "--all")
+    {
+        return Vec::new();
+    }
+
+    let mut specs = Vec::new();
+    let mut args = cargo_args.iter();
+    while let Some(arg) = args.next() {
+        if *arg == "-p" || *arg == "--package" {
+            specs.extend(args.next().map(|s| s.as_str()));
+        } else if let Some(spec) = arg.strip_prefix("--package=") {
+            specs.push(spec);
+        } else if let Some(spec) = arg.strip_prefix("-p").filter(|s| !s.is_empty()) {
+            specs.push(spec);
         }
     }
 
-    shell.verbose(|shell| {
-        let duration = start_time.elapsed();
-        let secs = duration.as_secs();
-        let d = if secs >= 60 {
-            format!("{}m {:02}s", secs / 60, secs % 60)
-        } else {
-            format!("{}.{:02}s", secs, duration.subsec_nanos() / 10_000_000)
-        };
-        let t = targets
+    if specs.is_empty() {
+        let manifest = dunce::canonicalize(cargo_manifest).unwrap_or(cargo_manifest.to_path_buf());
+        return metadata
+            .packages
             .iter()
-            .map(|(target, _)| target.to_string())
-            .collect::<Vec<_>>()
-            .join(", ");
-
-        shell.status("Finished", format!("targets ({t}) in {d}",))
-    })?;
+            .filter(|p| p.manifest_path.as_std_path() == manifest)
+            .collect();
+    }
 
-    Ok(())
+    // Specs may also be globs or carry a version, so only match plain package names
+    let packages = specs
+        .iter()
+        .map(|spec| metadata.packages.iter().find(|p| p.name == *spec))
+        .collect::<Option<Vec<_>>>();
+    packages.unwrap_or_default()
 }
 
 /// Check whether the produced artifact is of use to use (has to be of type `cdylib`).
+///
+/// With `--all-targets` or `cargo test`, a cdylib is also built as a test harness, which is
+/// reported with the same crate type but produces an executable rather than a library.
 fn artifact_is_cdylib(artifact: &Artifact) -> bool {
-    artifact.target.crate_types.iter().any(|ty| ty == "cdylib")
+    !artifact.profile.test && artifact.target.crate_types.iter().any(|ty| ty == "cdylib")
+}
+
+/// The files of an artifact to copy to the output dir, given the kinds being collected: the `.so`
+/// of a `cdylib`, or the executable of a binary or example. Test harnesses are never collected.
+fn collected_files<'a>(artifact: &'a Artifact, kinds: &[ArtifactKind]) -> Vec<&'a Utf8Path> {
+    if artifact.profile.test {
+        return Vec::new();
+    }
+
+    let mut files = Vec::new();
+    if kinds.contains(&ArtifactKind::Cdylib) && artifact_is_cdylib(artifact) {
+        files.extend(
+            artifact
+                .filenames
+                .iter()
+                .filter(|name| name.extension() == Some("so"))
+                .map(|name| name.as_path()),
+        );
+    }
+
+    let is_kind = |kind: &str| artifact.target.kind.iter().any(|k| k == kind);
+    let executable = (kinds.contains(&ArtifactKind::Bin) && is_kind("bin"))
+        || (kinds.contains(&ArtifactKind::Example) && is_kind("example"));
+    if executable {
+        files.extend(artifact.executable.as_deref());
+    }
+
+    files
+}
+
+/// Copy an unstripped library into the symbols dir, in a directory named after its build ID when
+/// it has one, for uploading to crash reporting services. The stripped library that is shipped
+/// keeps the same build ID, which is how the two are matched up.
+fn copy_symbols(
+    shell: &mut Shell,
+    ndk_home: &Path,
+    target: &Target,
+    legacy_toolchain: bool,
+    file: &Utf8Path,
+    symbols_dir: &Path,
+) -> anyhow::Result<SymbolFile> {
+    let name = file.file_name().unwrap();
+    let build_id = crate::cargo::build_id(
+        ndk_home,
+        target.triple(),
+        legacy_toolchain,
+        file.as_std_path(),
+    );
+    if build_id.is_none() {
+        shell.warn(format!(
+            "no build ID found in {file}, so its symbols are kept by file name only"
+        ))?;
+    }
+
+    let relative = [target.to_string()]
+        .into_iter()
+        .chain(build_id.clone())
+        .chain([name.to_string()])
+        .collect::<Vec<_>>();
+    let dest = relative
+        .iter()
+        .fold(symbols_dir.to_path_buf(), |path, part| path.join(part));
+    let dir = dest.parent().unwrap();
+    fs::create_dir_all(dir).with_context(|| format!("failed to create {dir:?}"))?;
+
+    if !is_fresh(file.as_std_path(), &dest)? {
+        shell.verbose(|shell| shell.status("Symbols", format!("{file} -> {}", dest.display())))?;
+        replace_file(&dest, |tmp| {
+            fs::copy(file, tmp)
+                .with_context(|| format!("failed to copy {file:?} over to {tmp:?}"))?;
+            Ok(())
+        })?;
+    }
+
+    Ok(SymbolFile {
+        build_id,
+        abi: target.to_string(),
+        name: name.to_string(),
+        path: relative.join("/"),
+    })
+}
+
+/// Write a compressed copy of a library copied to the output directory beside it.
+fn compress(shell: &mut Shell, compression: Compression, path: &Path) -> anyhow::Result<()> {
+    shell.verbose(|shell| {
+        shell.status("Compressing", format!("{} ({compression})", path.display()))
+    })?;
+    compression.write_sidecar(path)?;
+    Ok(())
+}
+
+/// Write `dest` by way of a temporary file beside it, which is renamed into place once `write` has
+/// finished with it, so that `dest` is never seen half-written.
+pub(crate) fn replace_file(
+    dest: &Path,
+    write: impl FnOnce(&Path) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let name = dest.file_name().unwrap().to_string_lossy();
+    let tmp = dest.with_file_name(format!(".{name}.{}.tmp", std::process::id()));
+
+    let result = write(&tmp).and_then(|()| {
+        fs::rename(&tmp, dest).with_context(|| format!("failed to move {tmp:?} over to {dest:?}"))
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
 }
 
 // Check if the source file has changed and should be copied over to the destination path.
-fn is_fresh(src: &Utf8Path, dest: &Path) -> anyhow::Result<bool> {
+fn is_fresh(src: &Path, dest: &Path) -> anyhow::Result<bool> {
     if !dest.exists() {
         return Ok(false);
     }
@@ -829,3 +3438,137 @@ fn is_fresh(src: &Utf8Path, dest: &Path) -> anyhow::Result<bool> {
 
     Ok(src <= dest)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An artifact as cargo reports it in its JSON messages.
+    fn artifact(kind: &str, crate_type: &str, test: bool, filenames: &[&str]) -> Artifact {
+        let executable = filenames.iter().find(|name| !name.ends_with(".so"));
+        let message = serde_json::json!({
+            "reason": "compiler-artifact",
+            "package_id": "foo 0.1.0 (path+file:///foo)",
+            "manifest_path": "/foo/Cargo.toml",
+            "target": {
+                "kind": [kind],
+                "crate_types": [crate_type],
+                "name": "foo",
+                "src_path": "/foo/src/lib.rs",
+                "edition": "2021",
+                "doc": true,
+                "doctest": false,
+                "test": true,
+            },
+            "profile": {
+                "opt_level": "0",
+                "debuginfo": 2,
+                "debug_assertions": true,
+                "overflow_checks": true,
+                "test": test,
+            },
+            "features": [],
+            "filenames": filenames,
+            "executable": executable,
+            "fresh": false,
+        });
+        match serde_json::from_value(message).unwrap() {
+            cargo_metadata::Message::CompilerArtifact(artifact) => artifact,
+            message => panic!("not an artifact: {message:?}"),
+        }
+    }
+
+    #[test]
+    fn only_the_cdylib_is_copied_from_all_targets() {
+        let artifacts = [
+            artifact("cdylib", "cdylib", false, &["/out/libfoo.so"]),
+            artifact("cdylib", "cdylib", true, &["/out/deps/foo-1234"]),
+            artifact("bin", "bin", false, &["/out/foo"]),
+        ];
+        let copied = |kinds: &[ArtifactKind]| {
+            artifacts
+                .iter()
+                .flat_map(|a| collected_files(a, kinds))
+                .map(Utf8Path::as_str)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            artifacts.iter().filter(|a| artifact_is_cdylib(a)).count(),
+            1
+        );
+        assert_eq!(copied(&[ArtifactKind::Cdylib]), ["/out/libfoo.so"]);
+        assert_eq!(
+            copied(&[ArtifactKind::Cdylib, ArtifactKind::Bin]),
+            ["/out/libfoo.so", "/out/foo"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn invalid_utf8_env_values_are_found_unless_written_as_bytes() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let env = BTreeMap::from([
+            ("CC".to_string(), OsString::from("/ndk/clang")),
+            (
+                "AR".to_string(),
+                OsStr::from_bytes(b"/nd\xffk/llvm-ar").to_os_string(),
+            ),
+        ]);
+        for format in [EnvFormat::Json, EnvFormat::Bash, EnvFormat::PowerShell] {
+            assert_eq!(inexact_env_values(&env, format), ["AR"]);
+        }
+        assert!(inexact_env_values(&env, EnvFormat::Null).is_empty());
+    }
+
+    #[test]
+    fn target_arg_takes_an_optional_platform() {
+        let arg = "arm64-v8a:30".parse::<TargetArg>().unwrap();
+        assert_eq!(arg.target, Target::Arm64V8a);
+        assert_eq!(arg.platform, Some(30));
+
+        let arg = "armeabi-v7a".parse::<TargetArg>().unwrap();
+        assert_eq!(arg.target, Target::ArmeabiV7a);
+        assert_eq!(arg.platform, None);
+    }
+
+    #[test]
+    fn target_arg_rejects_invalid_platform() {
+        assert!("arm64-v8a:thirty".parse::<TargetArg>().is_err());
+        assert!("arm64-v8a:".parse::<TargetArg>().is_err());
+        assert!("arm64-v8a:300".parse::<TargetArg>().is_err());
+        assert!("arm65:30".parse::<TargetArg>().is_err());
+    }
+
+    #[test]
+    fn platform_per_target_overrides_the_default() {
+        let platforms = Platforms {
+            default: 21,
+            by_target: vec![(Target::Arm64V8a, 30)],
+        };
+        assert_eq!(platforms.get(&Target::Arm64V8a), 30);
+        assert_eq!(platforms.get(&Target::ArmeabiV7a), 21);
+    }
+
+    #[test]
+    fn riscv64_platform_is_raised_to_its_minimum() {
+        let mut platforms = Platforms {
+            default: 21,
+            by_target: vec![(Target::Arm64V8a, 30)],
+        };
+        let raised =
+            platforms.raise_to_minimum(&[Target::Arm64V8a, Target::Riscv64, Target::X86_64]);
+        assert_eq!(raised, [(Target::Riscv64, 21)]);
+        assert_eq!(platforms.get(&Target::Riscv64), 35);
+        assert_eq!(platforms.get(&Target::Arm64V8a), 30);
+        assert_eq!(platforms.get(&Target::X86_64), 21);
+
+        let mut platforms = Platforms {
+            default: 21,
+            by_target: vec![(Target::Riscv64, 36)],
+        };
+        assert!(platforms.raise_to_minimum(&[Target::Riscv64]).is_empty());
+        assert_eq!(platforms.get(&Target::Riscv64), 36);
+    }
+}
@@ -0,0 +1,80 @@
+use std::{
+    fmt::Display,
+    fs::File,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str::FromStr,
+};
+
+use anyhow::Context;
+
+/// The format of the compressed copies written beside each library by `--compress`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Compression {
+    Zstd,
+    Gzip,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "zstd" => Compression::Zstd,
+            "gzip" => Compression::Gzip,
+            _ => return Err(format!("Unsupported compression: '{s}'")),
+        })
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Compression::Zstd => "zstd",
+            Compression::Gzip => "gzip",
+        })
+    }
+}
+
+impl Compression {
+    fn extension(self) -> &'static str {
+        match self {
+            Compression::Zstd => "zst",
+            Compression::Gzip => "gz",
+        }
+    }
+
+    /// The compressed copy of `path`, as in `libfoo.so.zst`.
+    pub(crate) fn sidecar(self, path: &Path) -> PathBuf {
+        let mut name = path.file_name().unwrap().to_os_string();
+        name.push(".");
+        name.push(self.extension());
+        path.with_file_name(name)
+    }
+
+    /// Write the compressed copy of `path` beside it, leaving `path` itself as it is, using the
+    /// `zstd` or `gzip` found on the `PATH`.
+    pub(crate) fn write_sidecar(self, path: &Path) -> anyhow::Result<PathBuf> {
+        let dest = self.sidecar(path);
+        crate::cli::replace_file(&dest, |tmp| {
+            let output = File::create(tmp).with_context(|| format!("failed to create {tmp:?}"))?;
+            let mut command = Command::new(self.to_string());
+            match self {
+                Compression::Zstd => command.args(["-q", "-c", "-19"]),
+                // No name or timestamp in the header, so the same library compresses the same
+                Compression::Gzip => command.args(["-c", "-n", "-9"]),
+            };
+            let status = command
+                .arg(path)
+                .stdout(output)
+                .stdin(Stdio::null())
+                .status()
+                .with_context(|| format!("failed to run {self}; is it installed?"))?;
+            if !status.success() {
+                anyhow::bail!("{self} failed to compress {path:?} ({status})");
+            }
+            Ok(())
+        })?;
+        Ok(dest)
+    }
+}
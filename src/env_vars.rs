@@ -0,0 +1,330 @@
+use serde::Serialize;
+
+/// The default for `--output-dir`, so one environment can set it for every invocation.
+pub(crate) const OUTPUT_DIR_ENV: &str = "CARGO_NDK_OUTPUT_DIR";
+
+/// The default for `cargo ndk-env --format`.
+pub(crate) const OUT_FORMAT_ENV: &str = "CARGO_NDK_OUT_FORMAT";
+
+/// The default for `--strip`.
+pub(crate) const STRIP_ENV: &str = "CARGO_NDK_STRIP";
+
+/// An environment variable that cargo-ndk reads.
+#[derive(Debug, Serialize)]
+pub(crate) struct ReadVar {
+    pub name: &'static str,
+    pub flag: Option<&'static str>,
+    pub default: Option<&'static str>,
+    pub description: &'static str,
+}
+
+/// An environment variable that cargo-ndk sets for the build, where `<triple>` is the Rust target
+/// triple and `<TRIPLE>` the same upper-cased with underscores.
+#[derive(Debug, Serialize)]
+pub(crate) struct SetVar {
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+pub(crate) const READ: &[ReadVar] = &[
+    ReadVar {
+        name: "ANDROID_NDK_HOME",
+        flag: Some("--ndk-home"),
+        default: Some("the newest NDK in the Android SDK"),
+        description: "the NDK to build with; ANDROID_NDK_ROOT, ANDROID_NDK_PATH and NDK_HOME are also read",
+    },
+    ReadVar {
+        name: "ANDROID_HOME",
+        flag: None,
+        default: Some("the platform's standard SDK location"),
+        description: "the Android SDK, to find an NDK and adb in; ANDROID_SDK_ROOT and ANDROID_SDK_HOME are also read",
+    },
+    ReadVar {
+        name: "ANDROID_SERIAL",
+        flag: Some("--adb-serial"),
+        default: Some("the only connected device"),
+        description: "the device to push to and run on, as read by adb itself",
+    },
+    ReadVar {
+        name: OUTPUT_DIR_ENV,
+        flag: Some("--output-dir"),
+        default: None,
+        description: "the directory libraries are copied to, in a directory per ABI",
+    },
+    ReadVar {
+        name: OUT_FORMAT_ENV,
+        flag: Some("cargo ndk-env --format"),
+        default: Some("bash"),
+        description: "how cargo ndk-env prints the environment",
+    },
+    ReadVar {
+        name: STRIP_ENV,
+        flag: Some("--strip"),
+        default: Some("all"),
+        description: "what to strip from the libraries copied to the output directory",
+    },
+    ReadVar {
+        name: "CARGO",
+        flag: Some("--cargo"),
+        default: None,
+        description: "the cargo binary to build with, as set by cargo when it runs cargo-ndk",
+    },
+    ReadVar {
+        name: "CARGO_HOME",
+        flag: None,
+        default: Some("~/.cargo"),
+        description: "where cargo's own config is read from, as cargo does",
+    },
+    ReadVar {
+        name: "CARGO_TERM_COLOR",
+        flag: Some("--color"),
+        default: Some("auto"),
+        description: "whether output is colored, which is passed on to cargo",
+    },
+    ReadVar {
+        name: "RUSTC",
+        flag: None,
+        default: Some("rustc"),
+        description: "the rustc whose sysroot is checked for the Android targets; also RUSTC_WRAPPER and RUSTC_WORKSPACE_WRAPPER, shown with -vv",
+    },
+    ReadVar {
+        name: "RUSTFLAGS",
+        flag: None,
+        default: None,
+        description: "flags for rustc, kept along with those for --target-cpu and --target-feature; also CARGO_BUILD_RUSTFLAGS",
+    },
+    ReadVar {
+        name: "CARGO_ENCODED_RUSTFLAGS",
+        flag: None,
+        default: None,
+        description: "flags for rustc separated by 0x1f, which take precedence over RUSTFLAGS as in cargo",
+    },
+    ReadVar {
+        name: "CARGO_TARGET_<TRIPLE>_RUSTFLAGS",
+        flag: None,
+        default: None,
+        description: "flags for rustc for the target, which take precedence over CARGO_BUILD_RUSTFLAGS as in cargo",
+    },
+    ReadVar {
+        name: "CARGO_UNSTABLE_BUILD_STD",
+        flag: None,
+        default: None,
+        description: "skips the check for the Android targets, as the standard library is built from source",
+    },
+    ReadVar {
+        name: "MSYSTEM",
+        flag: None,
+        default: None,
+        description: "set by MSYS2, where paths are given to the build with forward slashes",
+    },
+    ReadVar {
+        name: "CYGWIN",
+        flag: None,
+        default: None,
+        description: "set by Cygwin, where paths are given to the build with forward slashes",
+    },
+    ReadVar {
+        name: "CFLAGS_<triple>",
+        flag: None,
+        default: None,
+        description: "C flags added after cargo-ndk's own; also CXXFLAGS_<triple>",
+    },
+    ReadVar {
+        name: "CARGO_NDK_KEEP_LINK_TEMPS",
+        flag: None,
+        default: None,
+        description: "keep clang's intermediate files when linking",
+    },
+    ReadVar {
+        name: "CARGO_NDK_NO_PANIC_HOOK",
+        flag: None,
+        default: None,
+        description: "show Rust's own panic message instead of cargo-ndk's",
+    },
+];
+
+pub(crate) const SET: &[SetVar] = &[
+    SetVar {
+        name: "CC_<triple>",
+        description: "the NDK's clang, for the cc crate; also CXX_<triple>",
+    },
+    SetVar {
+        name: "CFLAGS_<triple>",
+        description: "clang's --target with the API level, for the cc crate; also CXXFLAGS_<triple>",
+    },
+    SetVar {
+        name: "AR_<triple>",
+        description: "the NDK's ar, for the cc crate; also RANLIB_<triple>",
+    },
+    SetVar {
+        name: "CLANG_PATH",
+        description: "the NDK's clang, for bindgen",
+    },
+    SetVar {
+        name: "BINDGEN_EXTRA_CLANG_ARGS_<triple>",
+        description: "the NDK's sysroot and includes, for bindgen (with --bindgen)",
+    },
+    SetVar {
+        name: "CARGO_TARGET_<TRIPLE>_AR",
+        description: "the NDK's ar, for cargo",
+    },
+    SetVar {
+        name: "CARGO_TARGET_<TRIPLE>_LINKER",
+        description: "cargo-ndk itself, which links with the NDK's clang (unless --no-linker-override)",
+    },
+    SetVar {
+        name: "CARGO_TARGET_<TRIPLE>_RUNNER",
+        description: "cargo-ndk-runner, which runs binaries on a device, for test, bench and run (unless a runner is already configured)",
+    },
+    SetVar {
+        name: "PKG_CONFIG_ALLOW_CROSS_<triple>",
+        description: "lets the pkg-config crate cross compile (unless --no-pkg-config-setup); also PKG_CONFIG_SYSROOT_DIR_<triple> and PKG_CONFIG_PATH_<triple>",
+    },
+    SetVar {
+        name: "<TRIPLE>_OPENSSL_DIR",
+        description: "the OpenSSL from --openssl-sysroot, for openssl-sys; also <TRIPLE>_OPENSSL_INCLUDE_DIR and <TRIPLE>_OPENSSL_LIB_DIR",
+    },
+    SetVar {
+        name: "CARGO_NDK_ANDROID_PLATFORM",
+        description: "the API level; also ANDROID_PLATFORM and ANDROID_NATIVE_API_LEVEL, for CMake",
+    },
+    SetVar {
+        name: "CARGO_NDK_ANDROID_TARGET",
+        description: "the ABI, e.g. arm64-v8a; also ANDROID_ABI, for CMake",
+    },
+    SetVar {
+        name: "CARGO_NDK_CMAKE_TOOLCHAIN_PATH",
+        description: "the NDK's android.toolchain.cmake",
+    },
+    SetVar {
+        name: "CARGO_NDK_OUTPUT_PATH",
+        description: "the output directory from --output-dir",
+    },
+    SetVar {
+        name: "CARGO_NDK_SYSROOT_PATH",
+        description: "the sysroot in the NDK",
+    },
+    SetVar {
+        name: "CARGO_NDK_SYSROOT_TARGET",
+        description: "the target's name in the sysroot",
+    },
+    SetVar {
+        name: "CARGO_NDK_SYSROOT_LIBS_PATH",
+        description: "the target's libraries in the sysroot",
+    },
+];
+
+/// The environment variables cargo-ndk reads, for `--help`.
+pub(crate) fn help() -> String {
+    let width = READ.iter().map(|var| var.name.len()).max().unwrap_or(0);
+    let mut help = "Environment variables:".to_string();
+    for var in READ {
+        help.push_str(&format!("\n  {:width$}  {}", var.name, var.description));
+    }
+    help
+}
+
+/// Print every environment variable cargo-ndk reads and sets, for `--list-env-vars`.
+pub(crate) fn print(json: bool) -> anyhow::Result<()> {
+    if json {
+        #[derive(Serialize)]
+        struct EnvVars {
+            read: &'static [ReadVar],
+            set: &'static [SetVar],
+        }
+        let vars = EnvVars {
+            read: READ,
+            set: SET,
+        };
+        println!("{}", serde_json::to_string_pretty(&vars)?);
+        return Ok(());
+    }
+
+    let rows = READ
+        .iter()
+        .map(|var| {
+            [
+                var.name,
+                var.flag.unwrap_or("-"),
+                var.default.unwrap_or("-"),
+                var.description,
+            ]
+        })
+        .collect::<Vec<_>>();
+    println!("Read by cargo-ndk:\n");
+    print_table(["NAME", "FLAG", "DEFAULT", "DESCRIPTION"], &rows);
+
+    let rows = SET
+        .iter()
+        .map(|var| [var.name, var.description])
+        .collect::<Vec<_>>();
+    println!("\nSet for the build:\n");
+    print_table(["NAME", "DESCRIPTION"], &rows);
+    Ok(())
+}
+
+fn print_table<const N: usize>(header: [&str; N], rows: &[[&str; N]]) {
+    let mut widths = header.map(str::len);
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in [&header].into_iter().chain(rows) {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The names of the environment variables read with a literal name in `source`.
+    fn literal_reads(source: &str) -> Vec<&str> {
+        source
+            .match_indices("env::var")
+            .filter_map(|(i, _)| {
+                let rest = &source[i + "env::var".len()..];
+                let rest = rest
+                    .strip_prefix("_os")
+                    .unwrap_or(rest)
+                    .strip_prefix("(\"")?;
+                rest.split('"').next()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_variable_read_is_listed() {
+        let src = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let sources = [src.clone(), src.join("bin")]
+            .iter()
+            .flat_map(|dir| std::fs::read_dir(dir).unwrap())
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "rs"))
+            .map(|path| std::fs::read_to_string(path).unwrap())
+            .collect::<Vec<_>>();
+        assert!(!sources.is_empty());
+
+        // Those set by cargo-ndk and read back, and its own internal ones, needn't be listed
+        let listed = |name: &str| {
+            name.starts_with('_')
+                || SET.iter().any(|var| var.name == name)
+                || READ
+                    .iter()
+                    .any(|var| var.name == name || var.description.contains(name))
+        };
+        let unlisted = sources
+            .iter()
+            .flat_map(|source| literal_reads(source))
+            .filter(|name| !listed(name))
+            .collect::<Vec<_>>();
+        assert!(unlisted.is_empty(), "not listed in READ: {unlisted:?}");
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use gumdrop::Options;
+
+use crate::{
+    meta::{Ndk, Target},
+    shell::Shell,
+};
+
+#[derive(Debug, Options)]
+pub(crate) struct ArgsInit {
+    #[options(help = "show help information")]
+    help: bool,
+
+    #[options(
+        no_short,
+        help = "the package name, which defaults to the name of the directory"
+    )]
+    name: Option<String>,
+
+    #[options(
+        no_short,
+        help = "comma-separated targets to build by default, e.g. arm64-v8a,x86_64 (default: armeabi-v7a,arm64-v8a)"
+    )]
+    targets: Option<String>,
+
+    #[options(
+        free,
+        help = "the directory to create the crate in (default: the current directory)"
+    )]
+    path: Option<PathBuf>,
+}
+
+const LIB_RS: &str = r#"use std::ffi::c_void;
+
+/// Called by the JVM when the library is loaded with `System.loadLibrary`, which is where native
+/// methods can be registered. Returns the JNI version this library needs.
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "system" fn JNI_OnLoad(_vm: *mut c_void, _reserved: *mut c_void) -> i32 {
+    // JNI_VERSION_1_6
+    0x0001_0006
+}
+
+#[no_mangle]
+pub extern "C" fn example() {
+    println!("Hello Android!");
+}
+"#;
+
+const CONFIG_TOML: &str = r#"# cargo-ndk sets the linker, compiler and sysroot for each Android target itself, so no
+# `[target.<triple>] linker` entries are needed here. Build with, for example:
+#
+#     cargo ndk -o ../app/src/main/jniLibs build --release
+#
+# Settings that apply to every target can still go here, such as:
+#
+# [target.aarch64-linux-android]
+# rustflags = ["-Ctarget-cpu=cortex-a76"]
+"#;
+
+fn print_usage() {
+    println!("cargo-ndk <https://github.com/bbqsrc/cargo-ndk>\n\nUsage: cargo ndk init [OPTIONS] [PATH]\n       cargo ndk new [OPTIONS] <PATH>\n");
+    println!("{}", ArgsInit::usage());
+}
+
+/// Scaffold a crate that `cargo ndk` can build for Android, for `cargo ndk init` (in an existing
+/// directory, by default the current one) and `cargo ndk new` (in a new directory).
+pub(crate) fn run(
+    shell: &mut Shell,
+    subcommand: &str,
+    args: &[String],
+    extra_targets: &[Target],
+    platform: Option<u8>,
+) -> anyhow::Result<()> {
+    let args = match ArgsInit::parse_args_default(args) {
+        Ok(args) if args.help => {
+            print_usage();
+            std::process::exit(0);
+        }
+        Ok(args) => args,
+        Err(e) => {
+            shell.error(e)?;
+            std::process::exit(2);
+        }
+    };
+
+    let platform = platform.unwrap_or(Ndk::default().platform);
+    let is_new = subcommand == "new";
+    let dir = match (args.path, is_new) {
+        (Some(path), _) => path,
+        (None, false) => PathBuf::from("."),
+        (None, true) => {
+            shell.error("`cargo ndk new` needs a path for the new crate")?;
+            std::process::exit(2);
+        }
+    };
+
+    let mut targets = match args.targets.as_deref() {
+        Some(targets) => match targets
+            .split(',')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(str::parse::<Target>)
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(v) => v,
+            Err(e) => {
+                shell.error(e)?;
+                std::process::exit(2);
+            }
+        },
+        None => Vec::new(),
+    };
+    targets.extend(extra_targets.iter().cloned());
+    if targets.is_empty() {
+        targets = crate::meta::default_targets();
+    }
+    let mut names = Vec::<String>::new();
+    for target in targets.iter().map(Target::to_string) {
+        if !names.contains(&target) {
+            names.push(target);
+        }
+    }
+
+    if is_new && dir.exists() {
+        shell.error(format!("destination {} already exists", dir.display()))?;
+        shell.note("use `cargo ndk init` to set up a crate in an existing directory")?;
+        std::process::exit(1);
+    }
+    if dir.join("Cargo.toml").exists() {
+        shell.error(format!(
+            "{} already exists; `cargo ndk init` only sets up new crates",
+            dir.join("Cargo.toml").display()
+        ))?;
+        shell.note(
+            "add `crate-type = [\"cdylib\"]` to its [lib] section to build it with cargo-ndk",
+        )?;
+        std::process::exit(1);
+    }
+
+    let name = match args.name {
+        Some(name) => name,
+        None => {
+            let dir = dunce::canonicalize(&dir).unwrap_or_else(|_| dir.clone());
+            dir.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        }
+    };
+    if !is_valid_package_name(&name) {
+        shell.error(format!("`{name}` isn't a valid package name"))?;
+        shell.note("pick another one with --name")?;
+        std::process::exit(2);
+    }
+
+    write_new(
+        &dir.join("Cargo.toml"),
+        &cargo_toml(&name, platform, &names),
+    )?;
+    write_new(&dir.join("src").join("lib.rs"), LIB_RS)?;
+    write_new(&dir.join(".cargo").join("config.toml"), CONFIG_TOML)?;
+
+    shell.status(
+        "Created",
+        format!("library `{name}` package for {}", names.join(", ")),
+    )?;
+    Ok(())
+}
+
+fn cargo_toml(name: &str, platform: u8, targets: &[String]) -> String {
+    let targets = targets
+        .iter()
+        .map(|t| format!("{t:?}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        r#"[package]
+name = "{name}"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+# This must contain at least cdylib for Android libraries to be generated.
+crate-type = ["cdylib"]
+
+# The defaults for `cargo ndk`, which can be overridden with -t and --platform.
+[package.metadata.ndk]
+platform = {platform}
+targets = [{targets}]
+"#
+    )
+}
+
+/// Write a file, creating its directory, without replacing one that is already there.
+fn write_new(path: &Path, contents: &str) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .and_then(|mut file| std::io::Write::write_all(&mut file, contents.as_bytes()))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+fn is_valid_package_name(name: &str) -> bool {
+    name.chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
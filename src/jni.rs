@@ -0,0 +1,88 @@
+use cargo_metadata::camino::Utf8Path;
+
+use crate::shell::Shell;
+
+/// The symbol the JVM calls when a library is loaded, where natives can be registered by hand.
+const ON_LOAD: &str = "JNI_OnLoad";
+
+/// A Java class name, such as `com.example.Native`, mangled as the JVM does when looking up native
+/// methods, giving `com_example_Native`.
+pub(crate) fn mangle_class(class: &str) -> String {
+    let mut mangled = String::new();
+    for c in class.chars() {
+        match c {
+            '.' | '/' => mangled.push('_'),
+            '_' => mangled.push_str("_1"),
+            ';' => mangled.push_str("_2"),
+            '[' => mangled.push_str("_3"),
+            c if c.is_ascii_alphanumeric() => mangled.push(c),
+            c => {
+                let mut units = [0; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    mangled.push_str(&format!("_0{unit:04x}"));
+                }
+            }
+        }
+    }
+    mangled
+}
+
+/// Warn if a library exports nothing the JVM can bind to: no `JNI_OnLoad`, and no
+/// `Java_<class>_<method>` symbols for each expected class (or at all, without any).
+pub(crate) fn check_exports(
+    shell: &mut Shell,
+    file: &Utf8Path,
+    exports: &[String],
+    classes: &[String],
+) -> anyhow::Result<()> {
+    let has_on_load = exports.iter().any(|name| name == ON_LOAD);
+    let natives = exports
+        .iter()
+        .filter(|name| name.starts_with("Java_"))
+        .collect::<Vec<_>>();
+
+    if classes.is_empty() {
+        if !has_on_load && natives.is_empty() {
+            shell.warn(format!(
+                "{file} exports no JNI symbols ({ON_LOAD} or Java_...)"
+            ))?;
+            shell.note(
+                "native methods must be `#[no_mangle] pub extern \"system\" fn Java_<package>_<Class>_<method>`",
+            )?;
+        }
+        return Ok(());
+    }
+
+    for class in classes {
+        let prefix = format!("Java_{}_", mangle_class(class));
+        if natives.iter().any(|name| name.starts_with(&prefix)) {
+            continue;
+        }
+
+        if has_on_load {
+            shell.verbose(|shell| {
+                shell.note(format!(
+                    "{file} exports no {prefix}* symbols, so its natives for {class} are assumed to be registered by {ON_LOAD}"
+                ))
+            })?;
+            continue;
+        }
+
+        shell.warn(format!(
+            "{file} exports no native methods for {class}, expected symbols starting with {prefix}"
+        ))?;
+        if !natives.is_empty() {
+            shell.note(format!(
+                "it does export {}",
+                natives
+                    .iter()
+                    .take(5)
+                    .map(|name| name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use std::{fmt::Write as _, fs, path::Path};
+
+use anyhow::Context;
+
+use crate::{
+    meta::Target,
+    runner::{RunResult, TestOutcome},
+};
+
+/// Write the results of the binaries run on the device as a JUnit XML report, with one test
+/// suite per binary.
+pub(crate) fn write(path: &Path, results: &[(Target, RunResult)]) -> anyhow::Result<()> {
+    let mut suites = String::new();
+    let mut total_tests = 0;
+    let mut total_failures = 0;
+
+    for (target, result) in results {
+        let suite_name = format!("{target}/{}", result.name);
+        let failures = result
+            .tests
+            .iter()
+            .filter(|x| x.outcome == TestOutcome::Failed)
+            .count();
+        let skipped = result
+            .tests
+            .iter()
+            .filter(|x| x.outcome == TestOutcome::Ignored)
+            .count();
+
+        let mut cases = String::new();
+        for test in result.tests.iter() {
+            write!(
+                cases,
+                r#"    <testcase name="{}" classname="{}""#,
+                escape(&test.name),
+                escape(&suite_name)
+            )?;
+            match test.outcome {
+                TestOutcome::Passed => cases.push_str("/>\n"),
+                TestOutcome::Ignored => cases.push_str(">\n      <skipped/>\n    </testcase>\n"),
+                TestOutcome::Failed => writeln!(
+                    cases,
+                    ">\n      <failure message=\"test failed\">{}</failure>\n    </testcase>",
+                    escape(test.output.as_deref().unwrap_or_default())
+                )?,
+            }
+        }
+
+        // A binary that failed without any test failing (e.g. it crashed, or isn't a libtest
+        // binary) still needs to show up as a failure.
+        let (tests, failures) = if result.code != 0 && failures == 0 {
+            writeln!(
+                cases,
+                r#"    <testcase name="{0}" classname="{0}">
+      <failure message="exited with code {1}"/>
+    </testcase>"#,
+                escape(&suite_name),
+                result.code
+            )?;
+            (result.tests.len() + 1, 1)
+        } else {
+            (result.tests.len(), failures)
+        };
+
+        total_tests += tests;
+        total_failures += failures;
+        write!(
+            suites,
+            "  <testsuite name=\"{}\" tests=\"{tests}\" failures=\"{failures}\" skipped=\"{skipped}\">\n{cases}  </testsuite>\n",
+            escape(&suite_name)
+        )?;
+    }
+
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites tests=\"{total_tests}\" failures=\"{total_failures}\">\n{suites}</testsuites>\n"
+    );
+    fs::write(path, xml).with_context(|| format!("failed to write {path:?}"))
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // Control characters (such as ANSI colour codes) aren't allowed in XML 1.0.
+            c if c.is_control() && !matches!(c, '\n' | '\r' | '\t') => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
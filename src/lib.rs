@@ -1,4 +1,19 @@
 pub mod cargo;
 pub mod cli;
+pub mod compress;
+pub mod env_vars;
+pub mod init;
+pub mod jni;
+pub mod junit;
+pub mod lock;
 pub mod meta;
+pub mod output_lock;
+pub mod process;
+pub mod runner;
 pub mod shell;
+pub mod size;
+pub mod smoke_test;
+pub mod source_hashes;
+pub mod symbols;
+pub mod timings;
+pub mod watch;
@@ -0,0 +1,78 @@
+use std::{fs, path::Path};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// The name of the lockfile, which is kept next to the workspace's `Cargo.lock`.
+pub(crate) const LOCKFILE_NAME: &str = "cargo-ndk.lock";
+
+const HEADER: &str =
+    "# This file is generated by `cargo ndk lock` and checked by `cargo ndk --locked`.\n";
+
+/// The NDK and build settings recorded by `cargo ndk lock`, so that `cargo ndk --locked` can
+/// catch the environment changing underneath a build, such as a CI image upgrading its NDK.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct NdkLock {
+    pub ndk_version: String,
+    /// The first line of `clang --version`, if clang could be run.
+    pub clang_version: Option<String>,
+    /// The NDK's prebuilt host toolchain directory, such as `linux-x86_64`.
+    pub host: String,
+    pub platform: u8,
+    pub targets: Vec<String>,
+}
+
+impl NdkLock {
+    pub(crate) fn read(path: &Path) -> anyhow::Result<Self> {
+        let data = fs::read_to_string(path).with_context(|| format!("failed to read {path:?}"))?;
+        toml::from_str(&data).with_context(|| format!("failed to parse {path:?}"))
+    }
+
+    pub(crate) fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let data = format!("{HEADER}{}", toml::to_string(self)?);
+        fs::write(path, data).with_context(|| format!("failed to write {path:?}"))
+    }
+
+    /// How the current environment differs from the locked one. Building only some of the locked
+    /// targets is fine.
+    pub(crate) fn drift(&self, current: &NdkLock) -> Vec<String> {
+        let mut drift = Vec::new();
+
+        if self.ndk_version != current.ndk_version {
+            drift.push(format!(
+                "NDK version is {}, but {} is locked",
+                current.ndk_version, self.ndk_version
+            ));
+        }
+        if self.clang_version != current.clang_version {
+            drift.push(format!(
+                "clang version is {}, but {} is locked",
+                current.clang_version.as_deref().unwrap_or("unknown"),
+                self.clang_version.as_deref().unwrap_or("unknown")
+            ));
+        }
+        if self.host != current.host {
+            drift.push(format!(
+                "NDK host toolchain is {}, but {} is locked",
+                current.host, self.host
+            ));
+        }
+        if self.platform != current.platform {
+            drift.push(format!(
+                "platform level is {}, but {} is locked",
+                current.platform, self.platform
+            ));
+        }
+        for target in &current.targets {
+            if !self.targets.contains(target) {
+                drift.push(format!(
+                    "target {target} is not locked, only {}",
+                    self.targets.join(", ")
+                ));
+            }
+        }
+
+        drift
+    }
+}
@@ -1,5 +1,5 @@
 use std::fmt::Display;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use serde::Deserialize;
@@ -10,7 +10,7 @@ const fn default_platform() -> u8 {
     21
 }
 
-fn default_targets() -> Vec<Target> {
+pub(crate) fn default_targets() -> Vec<Target> {
     vec![Target::ArmeabiV7a, Target::Arm64V8a]
 }
 
@@ -37,6 +37,8 @@ pub(crate) struct Ndk {
     #[serde(default = "default_targets")]
     targets: Vec<Target>,
 
+    output_dir: Option<PathBuf>,
+
     release: Option<NdkTarget>,
     debug: Option<NdkTarget>,
 }
@@ -46,6 +48,7 @@ impl Default for Ndk {
         Self {
             platform: default_platform(),
             targets: default_targets(),
+            output_dir: None,
             release: None,
             debug: None,
         }
@@ -61,6 +64,7 @@ struct NdkTarget {
 pub struct Config {
     pub platform: u8,
     pub targets: Vec<Target>,
+    pub output_dir: Option<PathBuf>,
 }
 
 impl Default for Config {
@@ -68,11 +72,12 @@ impl Default for Config {
         Self {
             platform: Ndk::default().platform,
             targets: default_targets(),
+            output_dir: None,
         }
     }
 }
 
-#[derive(Debug, Deserialize, Default, Clone)]
+#[derive(Debug, Deserialize, Default, Clone, PartialEq, Eq)]
 pub enum Target {
     #[serde(rename = "armeabi-v7a")]
     ArmeabiV7a,
@@ -83,6 +88,8 @@ pub enum Target {
     X86,
     #[serde(rename = "x86_64")]
     X86_64,
+    #[serde(rename = "riscv64")]
+    Riscv64,
 }
 
 impl FromStr for Target {
@@ -95,11 +102,13 @@ impl FromStr for Target {
             "arm64-v8a" => Target::Arm64V8a,
             "x86" => Target::X86,
             "x86_64" => Target::X86_64,
+            "riscv64" => Target::Riscv64,
             // match rust triple architectures
             "armv7-linux-androideabi" => Target::ArmeabiV7a,
             "aarch64-linux-android" => Target::Arm64V8a,
             "i686-linux-android" => Target::X86,
             "x86_64-linux-android" => Target::X86_64,
+            "riscv64-linux-android" => Target::Riscv64,
             _ => return Err(format!("Unsupported target: '{s}'")),
         })
     }
@@ -112,6 +121,7 @@ impl Display for Target {
             Target::Arm64V8a => "arm64-v8a",
             Target::X86 => "x86",
             Target::X86_64 => "x86_64",
+            Target::Riscv64 => "riscv64",
         })
     }
 }
@@ -123,6 +133,16 @@ impl Target {
             Target::Arm64V8a => "aarch64-linux-android",
             Target::X86 => "i686-linux-android",
             Target::X86_64 => "x86_64-linux-android",
+            Target::Riscv64 => "riscv64-linux-android",
+        }
+    }
+
+    /// The lowest platform level the target exists at, for those added to Android later than
+    /// the lowest level any NDK supports.
+    pub fn min_platform(&self) -> Option<u8> {
+        match self {
+            Target::Riscv64 => Some(35),
+            _ => None,
         }
     }
 }
@@ -150,8 +170,15 @@ pub(crate) fn config(
         ndk.debug.map_or_else(|| base_targets, |x| x.targets)
     };
 
+    // Relative to the manifest, wherever cargo-ndk is run from
+    let output_dir = ndk.output_dir.map(|dir| match cargo_toml_path.parent() {
+        Some(manifest_dir) => manifest_dir.join(dir),
+        None => dir,
+    });
+
     Ok(Config {
         platform: ndk.platform,
         targets,
+        output_dir,
     })
 }
@@ -0,0 +1,123 @@
+use std::{
+    fs::{File, OpenOptions},
+    path::Path,
+};
+
+use anyhow::Context;
+
+use crate::{meta::Target, shell::Shell};
+
+/// An advisory lock on the output directory for one ABI, for `--output-lock`, so that cargo-ndk
+/// invocations sharing an output directory take turns copying into it.
+///
+/// The lock file is kept beside the ABI's directory rather than in it, so it doesn't end up
+/// packaged with the libraries. Dropping the lock releases it.
+pub(crate) struct OutputLock {
+    _file: File,
+}
+
+impl OutputLock {
+    pub fn acquire(shell: &mut Shell, output_dir: &Path, target: &Target) -> anyhow::Result<Self> {
+        let path = output_dir.join(format!(".cargo-ndk-{target}.lock"));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("failed to open {path:?}"))?;
+
+        let locked = imp::try_lock(&file).with_context(|| format!("failed to lock {path:?}"))?;
+        if !locked {
+            shell.status(
+                "Blocking",
+                format!("waiting for file lock on {}", path.display()),
+            )?;
+            imp::lock(&file).with_context(|| format!("failed to lock {path:?}"))?;
+        }
+
+        Ok(Self { _file: file })
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::{fs::File, io, os::unix::io::AsRawFd};
+
+    /// Take the lock if it's free, returning whether it was.
+    pub fn try_lock(file: &File) -> io::Result<bool> {
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            return Ok(true);
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() == io::ErrorKind::WouldBlock {
+            Ok(false)
+        } else {
+            Err(e)
+        }
+    }
+
+    pub fn lock(file: &File) -> io::Result<()> {
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::{fs::File, io, mem, os::windows::io::AsRawHandle};
+
+    use windows_sys::Win32::{
+        Foundation::{ERROR_LOCK_VIOLATION, HANDLE},
+        Storage::FileSystem::{LockFileEx, LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY},
+        System::IO::OVERLAPPED,
+    };
+
+    fn lock_file(file: &File, flags: u32) -> io::Result<()> {
+        unsafe {
+            let mut overlapped: OVERLAPPED = mem::zeroed();
+            let ok = LockFileEx(
+                file.as_raw_handle() as HANDLE,
+                flags,
+                0,
+                u32::MAX,
+                u32::MAX,
+                &mut overlapped,
+            );
+            if ok != 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        }
+    }
+
+    /// Take the lock if it's free, returning whether it was.
+    pub fn try_lock(file: &File) -> io::Result<bool> {
+        match lock_file(file, LOCKFILE_EXCLUSIVE_LOCK | LOCKFILE_FAIL_IMMEDIATELY) {
+            Ok(()) => Ok(true),
+            Err(e) if e.raw_os_error() == Some(ERROR_LOCK_VIOLATION as i32) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn lock(file: &File) -> io::Result<()> {
+        lock_file(file, LOCKFILE_EXCLUSIVE_LOCK)
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod imp {
+    use std::{fs::File, io};
+
+    pub fn try_lock(_file: &File) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    pub fn lock(_file: &File) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,351 @@
+use std::{
+    io,
+    process::{Child, Command, ExitStatus},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::Instant,
+};
+
+/// A child process spawned in its own process group (a job object on Windows), so that it can
+/// be stopped along with everything it has started, such as the rustc and clang processes
+/// under cargo.
+///
+/// On Unix, when cargo-ndk is in the foreground of a terminal, the terminal already sends Ctrl-C
+/// and Ctrl-Z to everything in cargo-ndk's group, so the child is only given a group of its own
+/// when it must be `stoppable`, e.g. at a deadline. Interrupts and terminations sent to
+/// cargo-ndk are then passed on to the child's group, and in the foreground, so is stopping and
+/// continuing it. On Windows, the group is stopped when cargo-ndk exits, however that happens.
+///
+/// Dropping the group, once the child has exited, undoes all of this.
+pub(crate) struct Group {
+    inner: imp::Group,
+}
+
+impl Group {
+    pub fn spawn(command: &mut Command, stoppable: bool) -> io::Result<(Child, Self)> {
+        let (child, inner) = imp::Group::spawn(command, stoppable)?;
+        Ok((child, Self { inner }))
+    }
+}
+
+/// The signal that killed a process, such as an interrupt passed on to its group.
+pub(crate) fn killed_by(status: &ExitStatus) -> Option<i32> {
+    #[cfg(unix)]
+    {
+        std::os::unix::process::ExitStatusExt::signal(status)
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = status;
+        None
+    }
+}
+
+/// The code to exit with to pass on how a process exited: its own exit code, or 128 plus the
+/// signal that killed it, as a shell reports it.
+pub fn exit_code(status: &ExitStatus) -> i32 {
+    match (status.code(), killed_by(status)) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
+}
+
+/// Kills a process group if it is still running at a deadline.
+///
+/// Dropping the watchdog, once the process has exited, stops it.
+pub(crate) struct Watchdog {
+    _done: mpsc::Sender<()>,
+    fired: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    pub fn new(group: &Group, deadline: Instant) -> Self {
+        let (done, rx) = mpsc::channel::<()>();
+        let fired = Arc::new(AtomicBool::new(false));
+
+        let killer = group.inner.killer();
+        let thread_fired = Arc::clone(&fired);
+        thread::spawn(move || {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            // The sender is dropped along with the watchdog, which disconnects rather than times out
+            if let Err(mpsc::RecvTimeoutError::Timeout) = rx.recv_timeout(timeout) {
+                thread_fired.store(true, Ordering::SeqCst);
+                killer.kill();
+            }
+        });
+
+        Self { _done: done, fired }
+    }
+
+    /// Whether the deadline passed and the process group was killed.
+    pub fn fired(&self) -> bool {
+        self.fired.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::io;
+    use std::os::unix::process::CommandExt;
+    use std::process::{Child, Command, Stdio};
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    /// The signals passed on to the group when cargo-ndk receives them.
+    const FORWARDED_SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+    /// The group that signals are forwarded to, or 0 for none.
+    static FORWARD_TO: AtomicI32 = AtomicI32::new(0);
+
+    extern "C" fn forward_signal(signal: libc::c_int) {
+        let pgid = FORWARD_TO.load(Ordering::SeqCst);
+        if pgid > 0 {
+            unsafe {
+                libc::kill(-pgid, signal);
+            }
+        }
+    }
+
+    /// Stops the group along with cargo-ndk for Ctrl-Z, and continues it once cargo-ndk is
+    /// continued by the shell's `fg` or `bg`.
+    extern "C" fn stop_with_group(_signal: libc::c_int) {
+        let pgid = FORWARD_TO.load(Ordering::SeqCst);
+        unsafe {
+            if pgid > 0 {
+                libc::kill(-pgid, libc::SIGSTOP);
+            }
+            libc::raise(libc::SIGSTOP);
+            if pgid > 0 {
+                libc::kill(-pgid, libc::SIGCONT);
+            }
+        }
+    }
+
+    pub struct Group {
+        pgid: libc::pid_t,
+        previous_handlers: Vec<(libc::c_int, libc::sighandler_t)>,
+    }
+
+    impl Group {
+        pub fn spawn(command: &mut Command, stoppable: bool) -> io::Result<(Child, Self)> {
+            let foreground = is_foreground();
+            if foreground && !stoppable {
+                let child = command.spawn()?;
+                return Ok((
+                    child,
+                    Self {
+                        pgid: 0,
+                        previous_handlers: Vec::new(),
+                    },
+                ));
+            }
+
+            // The group is left in the background, so the terminal's signals only reach
+            // cargo-ndk, which passes them on. It can't read from the terminal there without
+            // being stopped, so it gets no stdin.
+            command.process_group(0);
+            if foreground {
+                command.stdin(Stdio::null());
+            }
+            let child = command.spawn()?;
+            let pgid = child.id() as libc::pid_t;
+
+            FORWARD_TO.store(pgid, Ordering::SeqCst);
+            let mut handlers = FORWARDED_SIGNALS
+                .iter()
+                .map(|&signal| (signal, forward_signal as extern "C" fn(libc::c_int)))
+                .collect::<Vec<_>>();
+            if foreground {
+                handlers.push((libc::SIGTSTP, stop_with_group));
+            }
+            let previous_handlers = handlers
+                .into_iter()
+                .map(|(signal, handler)| unsafe {
+                    let previous = libc::signal(signal, handler as libc::sighandler_t);
+                    // Leave ignored signals ignored, as when run with `nohup` or in the background
+                    if previous == libc::SIG_IGN {
+                        libc::signal(signal, libc::SIG_IGN);
+                    }
+                    (signal, previous)
+                })
+                .collect();
+
+            Ok((
+                child,
+                Self {
+                    pgid,
+                    previous_handlers,
+                },
+            ))
+        }
+
+        pub fn killer(&self) -> Killer {
+            Killer(self.pgid)
+        }
+    }
+
+    impl Drop for Group {
+        fn drop(&mut self) {
+            for &(signal, handler) in &self.previous_handlers {
+                unsafe { libc::signal(signal, handler) };
+            }
+            FORWARD_TO.store(0, Ordering::SeqCst);
+        }
+    }
+
+    /// Kills a group, or nothing for a child that was left in cargo-ndk's own group.
+    pub struct Killer(libc::pid_t);
+
+    impl Killer {
+        pub fn kill(&self) {
+            if self.0 > 0 {
+                unsafe {
+                    libc::kill(-self.0, libc::SIGKILL);
+                }
+            }
+        }
+    }
+
+    fn is_foreground() -> bool {
+        unsafe {
+            libc::isatty(libc::STDIN_FILENO) == 1
+                && libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp()
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::os::windows::io::AsRawHandle;
+    use std::process::{Child, Command};
+    use std::{io, mem, ptr};
+
+    use windows_sys::Win32::Foundation::{CloseHandle, HANDLE};
+    use windows_sys::Win32::System::JobObjects::{
+        AssignProcessToJobObject, CreateJobObjectW, JobObjectExtendedLimitInformation,
+        SetInformationJobObject, TerminateJobObject, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+        JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+    };
+
+    pub struct Group {
+        job: HANDLE,
+    }
+
+    impl Group {
+        pub fn spawn(command: &mut Command, _stoppable: bool) -> io::Result<(Child, Self)> {
+            let child = command.spawn()?;
+
+            // The job kills everything in it once its last handle is closed, which is when this
+            // is dropped or when cargo-ndk exits. Failing to set it up isn't fatal, as the child
+            // is otherwise left to run as usual.
+            unsafe {
+                let job = CreateJobObjectW(ptr::null(), ptr::null());
+                if job == 0 {
+                    return Ok((child, Self { job }));
+                }
+
+                let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = mem::zeroed();
+                info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+                let ok = SetInformationJobObject(
+                    job,
+                    JobObjectExtendedLimitInformation,
+                    &info as *const _ as *const _,
+                    mem::size_of_val(&info) as u32,
+                ) != 0
+                    && AssignProcessToJobObject(job, child.as_raw_handle() as HANDLE) != 0;
+                if !ok {
+                    CloseHandle(job);
+                    return Ok((child, Self { job: 0 }));
+                }
+
+                Ok((child, Self { job }))
+            }
+        }
+
+        pub fn killer(&self) -> Killer {
+            Killer(self.job)
+        }
+    }
+
+    impl Drop for Group {
+        fn drop(&mut self) {
+            if self.job != 0 {
+                unsafe { CloseHandle(self.job) };
+            }
+        }
+    }
+
+    pub struct Killer(HANDLE);
+
+    impl Killer {
+        pub fn kill(&self) {
+            if self.0 != 0 {
+                unsafe { TerminateJobObject(self.0, 1) };
+            }
+        }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod imp {
+    use std::io;
+    use std::process::{Child, Command};
+
+    pub struct Group;
+
+    impl Group {
+        pub fn spawn(command: &mut Command, _stoppable: bool) -> io::Result<(Child, Self)> {
+            Ok((command.spawn()?, Self))
+        }
+
+        pub fn killer(&self) -> Killer {
+            Killer
+        }
+    }
+
+    pub struct Killer;
+
+    impl Killer {
+        pub fn kill(&self) {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    fn sh(script: &str) -> ExitStatus {
+        Command::new("sh").args(["-c", script]).status().unwrap()
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn exit_code_is_passed_on_unchanged() {
+        assert_eq!(exit_code(&sh("exit 0")), 0);
+        assert_eq!(exit_code(&sh("exit 101")), 101);
+        assert_eq!(exit_code(&sh("exit 3")), 3);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn signal_is_passed_on_as_128_plus_signal() {
+        let status = sh("kill -TERM $$");
+        assert_eq!(killed_by(&status), Some(libc::SIGTERM));
+        assert_eq!(exit_code(&status), 128 + libc::SIGTERM);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn exit_code_is_passed_on_unchanged() {
+        let status = Command::new("cmd")
+            .args(["/C", "exit 101"])
+            .status()
+            .unwrap();
+        assert_eq!(exit_code(&status), 101);
+    }
+}
@@ -0,0 +1,518 @@
+use std::{
+    env,
+    ffi::OsString,
+    fs::{self, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{meta::Target, shell::Shell};
+
+/// Set by cargo-ndk to a file that the runner appends the outcome of each binary to.
+pub(crate) const REPORT_ENV: &str = "_CARGO_NDK_RUNNER_REPORT";
+
+/// Set by cargo-ndk for `--dry-run`, so what would be pushed and run is printed instead.
+pub(crate) const DRY_RUN_ENV: &str = "_CARGO_NDK_RUNNER_DRY_RUN";
+
+/// Set by cargo-ndk for `--quiet-adb` or `-q`, so only adb's errors are shown, not its progress.
+pub(crate) const QUIET_ADB_ENV: &str = "_CARGO_NDK_RUNNER_QUIET_ADB";
+
+/// Set by cargo-ndk for `--run-as`, to the package whose app context binaries are run in.
+pub(crate) const RUN_AS_ENV: &str = "_CARGO_NDK_RUNNER_RUN_AS";
+
+/// Set by cargo-ndk for `--device-shell-prefix`, to a command that binaries are run under.
+pub(crate) const SHELL_PREFIX_ENV: &str = "_CARGO_NDK_RUNNER_SHELL_PREFIX";
+
+/// Set by cargo-ndk for `--via-app-process`, to the Java class that `app_process` starts.
+pub(crate) const APP_PROCESS_CLASS_ENV: &str = "_CARGO_NDK_RUNNER_APP_PROCESS_CLASS";
+
+/// Set by cargo-ndk for `--app-process-classpath`, to a jar or APK with the class in it.
+pub(crate) const APP_PROCESS_CLASSPATH_ENV: &str = "_CARGO_NDK_RUNNER_APP_PROCESS_CLASSPATH";
+
+/// Set by cargo-ndk for `--device-dir`, to where binaries are pushed to on the device.
+pub(crate) const DEVICE_DIR_ENV: &str = "_CARGO_NDK_RUNNER_DEVICE_DIR";
+
+/// Where binaries are pushed to on the device by default. `/data/local/tmp` is the one location
+/// that the `adb shell` user can both write to and execute from on every Android version.
+const DEFAULT_DEVICE_DIR: &str = "/data/local/tmp/cargo-ndk";
+
+/// The exit status of the device's `sh` when it finds a binary but can't execute it, as when its
+/// directory is on a `noexec` mount.
+const CANNOT_EXECUTE: i32 = 126;
+
+pub(crate) fn device_dir() -> String {
+    env::var(DEVICE_DIR_ENV)
+        .ok()
+        .map(|dir| dir.trim_end_matches('/').to_string())
+        .filter(|dir| !dir.is_empty())
+        .unwrap_or_else(|| DEFAULT_DEVICE_DIR.to_string())
+}
+
+/// Where binaries are copied to and run from for `--run-as`, relative to the app's data dir.
+const APP_DIR: &str = "cargo-ndk";
+
+/// Find `adb` in the Android SDK, falling back to whatever is on the `PATH`.
+pub(crate) fn adb_path() -> PathBuf {
+    let adb = if cfg!(windows) { "adb.exe" } else { "adb" };
+
+    for var in ["ANDROID_HOME", "ANDROID_SDK_ROOT"] {
+        if let Some(sdk) = env::var_os(var) {
+            let path = Path::new(&sdk).join("platform-tools").join(adb);
+            if path.exists() {
+                return path;
+            }
+        }
+    }
+
+    PathBuf::from(adb)
+}
+
+/// Push a binary built for Android to the connected device and run it there.
+///
+/// cargo-ndk registers `cargo-ndk-runner` as the `CARGO_TARGET_<TRIPLE>_RUNNER`, so cargo invokes
+/// this with the path to the binary followed by its arguments for `cargo test`, `cargo run` and
+/// `cargo bench`. Which binaries get run is therefore decided by cargo's own target selection
+/// flags, such as `--test foo`, `--lib` or `--bin foo`.
+pub fn run(args: Vec<OsString>) -> anyhow::Result<()> {
+    let mut shell = Shell::new();
+
+    let mut args = args.into_iter();
+    let Some(binary) = args.next().map(PathBuf::from) else {
+        shell.error("No binary given to run on the device.")?;
+        std::process::exit(2);
+    };
+    let name = binary
+        .file_name()
+        .context("binary path has no file name")?
+        .to_string_lossy()
+        .to_string();
+    let device_dir = device_dir();
+    let device_path = format!("{device_dir}/{name}");
+    let adb = adb_path();
+
+    let dry_run = env::var_os(DRY_RUN_ENV).is_some();
+    let quiet = env::var_os(QUIET_ADB_ENV).is_some();
+    if quiet && !dry_run {
+        // Start the server up front, as any command does if it isn't running, so that its
+        // "daemon not running; starting now" messages can be hidden unless it fails to start.
+        let output = Command::new(&adb)
+            .arg("start-server")
+            .output()
+            .with_context(|| format!("failed to run {adb:?}"))?;
+        if !output.status.success() {
+            std::io::stderr().write_all(&output.stderr)?;
+            shell.error("failed to start the adb server")?;
+            std::process::exit(crate::process::exit_code(&output.status));
+        }
+    }
+
+    if dry_run {
+        shell.status(
+            "Would push",
+            format!("{} to {device_path}", binary.display()),
+        )?;
+    } else {
+        let status = Command::new(&adb)
+            .arg("push")
+            .arg(&binary)
+            .arg(&device_path)
+            .stdout(if quiet {
+                Stdio::null()
+            } else {
+                Stdio::inherit()
+            })
+            .status()
+            .with_context(|| format!("failed to run {adb:?}"))?;
+        if !status.success() {
+            shell.error(format!("failed to push {} to the device", binary.display()))?;
+            std::process::exit(crate::process::exit_code(&status));
+        }
+    }
+
+    let app_process = env::var(APP_PROCESS_CLASS_ENV).ok();
+    let classpath = match env::var_os(APP_PROCESS_CLASSPATH_ENV).filter(|_| app_process.is_some()) {
+        Some(jar) => {
+            let jar = PathBuf::from(jar);
+            let jar_name = jar
+                .file_name()
+                .context("classpath has no file name")?
+                .to_string_lossy()
+                .to_string();
+            let device_jar = format!("{device_dir}/{jar_name}");
+            if dry_run {
+                shell.status("Would push", format!("{} to {device_jar}", jar.display()))?;
+            } else {
+                let status = Command::new(&adb)
+                    .arg("push")
+                    .arg(&jar)
+                    .arg(&device_jar)
+                    .stdout(if quiet {
+                        Stdio::null()
+                    } else {
+                        Stdio::inherit()
+                    })
+                    .status()
+                    .with_context(|| format!("failed to run {adb:?}"))?;
+                if !status.success() {
+                    shell.error(format!("failed to push {} to the device", jar.display()))?;
+                    std::process::exit(crate::process::exit_code(&status));
+                }
+            }
+            Some(device_jar)
+        }
+        None => None,
+    };
+
+    // For `--via-app-process`, the binary is handed to a Java class started by `app_process`, in
+    // a runtime of its own with the framework on its classpath. The class decides how to run the
+    // binary, which it is given the path of, followed by the binary's arguments. The process is
+    // given a name to find it by afterwards, in case the runtime outlives the class.
+    let nice_name = format!("cargo-ndk-{name}");
+    let exe = match app_process.as_deref() {
+        Some(class) => format!(
+            "app_process -Djava.library.path=\"$PWD\" \"$PWD\" --nice-name={} {} \"$PWD\"/{}",
+            shell_quote(&nice_name),
+            shell_quote(class),
+            shell_quote(&name)
+        ),
+        None => format!("./{}", shell_quote(&name)),
+    };
+
+    // `adb shell` joins its arguments and runs them with the device's `sh`, so each argument must
+    // be quoted to reach the binary intact, e.g. libtest filters with spaces or shell characters.
+    // The prefix is a shell snippet of its own, such as `taskset f0` or `simpleperf record --`, so
+    // it is passed through as is.
+    // The classpath goes before any prefix, as the prefix's command passes it on
+    let mut run = classpath
+        .as_deref()
+        .map(|jar| format!("CLASSPATH={} ", shell_quote(jar)))
+        .unwrap_or_default();
+    match env::var(SHELL_PREFIX_ENV) {
+        Ok(prefix) if !prefix.trim().is_empty() => run.push_str(&format!("{prefix} {exe}")),
+        _ => run.push_str(&exe),
+    }
+    for arg in args {
+        run.push(' ');
+        run.push_str(&shell_quote(&arg.to_string_lossy()));
+    }
+
+    let command = match env::var(RUN_AS_ENV) {
+        // `run-as` starts in the app's data dir, with its user and SELinux context, which can't
+        // execute from `/data/local/tmp`. The binary is copied into the data dir instead, and
+        // removed again once it has run.
+        Ok(package) => {
+            let script = format!(
+                "mkdir -p {APP_DIR} && cd {APP_DIR} && cp {} {name} && chmod 700 {name} && {run}; \
+                 status=$?; rm -f {name}; exit $status",
+                shell_quote(&device_path),
+                name = shell_quote(&name),
+            );
+            format!(
+                "run-as {} sh -c {}",
+                shell_quote(&package),
+                shell_quote(&script)
+            )
+        }
+        Err(_) => format!("cd {} && {run}", shell_quote(&device_dir)),
+    };
+
+    if !dry_run && env::var_os("ANDROID_SERIAL").is_none() {
+        if let Some(device) = describe_device() {
+            shell.status("Running", format!("on {device}"))?;
+        }
+    }
+
+    if dry_run {
+        shell.status("Would run", format!("{} shell {command}", adb.display()))?;
+        return Ok(());
+    }
+
+    let mut child = Command::new(&adb)
+        .arg("shell")
+        .arg(command)
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to run {adb:?}"))?;
+
+    // Echo the output as it arrives, while picking out libtest's per-test results.
+    let mut output = Vec::new();
+    let reader = BufReader::new(child.stdout.take().context("no stdout available")?);
+    for line in reader.lines() {
+        let line = line?;
+        println!("{line}");
+        output.push(line);
+    }
+
+    let status = child.wait().context("adb crashed")?;
+    let code = crate::process::exit_code(&status);
+
+    if app_process.is_some() {
+        // Best effort, as there is nothing left to stop when the runtime exited along with the class
+        let _ = Command::new(&adb)
+            .arg("shell")
+            .arg(format!("pkill -f {} || true", shell_quote(&nice_name)))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+    }
+
+    // Pushing and chmod work on a `noexec` mount, so this is only found out when running
+    if code == CANNOT_EXECUTE {
+        shell.error(format!(
+            "{name} could not be executed on the device (exit status {CANNOT_EXECUTE})"
+        ))?;
+        if env::var_os(RUN_AS_ENV).is_none() {
+            shell.note(format!(
+                "{device_dir} may be on a mount that doesn't allow execution; pass --device-dir with a directory that does"
+            ))?;
+        }
+    }
+
+    if let Some(report) = env::var_os(REPORT_ENV) {
+        let result = RunResult {
+            name,
+            code,
+            tests: parse_libtest_output(&output),
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&report)
+            .with_context(|| format!("failed to open {report:?}"))?;
+        writeln!(file, "{}", serde_json::to_string(&result)?)?;
+    }
+
+    std::process::exit(code)
+}
+
+/// The serial number of the device adb would use, which is the one in `ANDROID_SERIAL` if that's
+/// set, or adb's complaint if it can't pick one, as when none or several are connected.
+pub(crate) fn device_serial() -> anyhow::Result<Result<String, String>> {
+    let adb = adb_path();
+    let output = Command::new(&adb)
+        .arg("get-serialno")
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("failed to run {adb:?}"))?;
+    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if output.status.success() && !stdout.is_empty() && stdout != "unknown" {
+        Ok(Ok(stdout))
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Ok(Err(stderr
+            .lines()
+            .map(|line| line.trim_start_matches("error: ").trim())
+            .find(|line| !line.is_empty() && !line.starts_with('*'))
+            .unwrap_or("no device found")
+            .to_string()))
+    }
+}
+
+/// The device adb picked when no serial was given, as `<serial> (<model>)`, for the logs to say
+/// where things ran. The model is asked of that device by its serial, in case another device
+/// connects in the meantime.
+pub(crate) fn describe_device() -> Option<String> {
+    let serial = device_serial().ok()?.ok()?;
+    let output = Command::new(adb_path())
+        .args(["-s", &serial, "shell", "getprop", "ro.product.model"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let model = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if output.status.success() && !model.is_empty() {
+        Some(format!("{serial} ({model})"))
+    } else {
+        Some(serial)
+    }
+}
+
+/// Push binaries copied to the output dir by `--push-all` to the device, each into a directory for
+/// its ABI under the runner's directory, and make them executable.
+pub(crate) fn push_all(
+    shell: &mut Shell,
+    binaries: &[(Target, PathBuf)],
+    quiet: bool,
+) -> anyhow::Result<()> {
+    let adb = adb_path();
+    let device_dir = device_dir();
+    for (target, binary) in binaries {
+        let name = binary
+            .file_name()
+            .context("binary path has no file name")?
+            .to_string_lossy();
+        let device_path = format!("{device_dir}/{target}/{name}");
+
+        let status = Command::new(&adb)
+            .arg("push")
+            .arg(binary)
+            .arg(&device_path)
+            .stdout(if quiet {
+                Stdio::null()
+            } else {
+                Stdio::inherit()
+            })
+            .status()
+            .with_context(|| format!("failed to run {adb:?}"))?;
+        if !status.success() {
+            shell.error(format!("failed to push {} to the device", binary.display()))?;
+            std::process::exit(crate::process::exit_code(&status));
+        }
+
+        // adb keeps the host file's mode, which isn't always executable, such as for a binary
+        // copied through a filesystem without permissions
+        let status = Command::new(&adb)
+            .arg("shell")
+            .arg(format!("chmod 755 {}", shell_quote(&device_path)))
+            .status()
+            .with_context(|| format!("failed to run {adb:?}"))?;
+        if !status.success() {
+            shell.error(format!("failed to make {device_path} executable"))?;
+            std::process::exit(crate::process::exit_code(&status));
+        }
+
+        shell.status("Pushed", format!("{} to {device_path}", binary.display()))?;
+    }
+
+    Ok(())
+}
+
+/// The outcome of running one binary on the device.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RunResult {
+    pub name: String,
+    pub code: i32,
+    pub tests: Vec<TestResult>,
+}
+
+/// The outcome of a single test, as reported by libtest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct TestResult {
+    pub name: String,
+    pub outcome: TestOutcome,
+    /// The test's captured output, for failed tests.
+    pub output: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum TestOutcome {
+    Passed,
+    Failed,
+    Ignored,
+}
+
+/// Pick the per-test results out of libtest's human-readable output.
+fn parse_libtest_output(lines: &[String]) -> Vec<TestResult> {
+    let mut tests = lines
+        .iter()
+        .filter_map(|line| line.strip_prefix("test "))
+        .filter_map(|line| line.split_once(" ... "))
+        .filter_map(|(name, result)| {
+            let outcome = if result == "ok" || result.starts_with("bench:") {
+                TestOutcome::Passed
+            } else if result == "FAILED" {
+                TestOutcome::Failed
+            } else if result.starts_with("ignored") {
+                TestOutcome::Ignored
+            } else {
+                return None;
+            };
+            Some(TestResult {
+                name: name.to_string(),
+                outcome,
+                output: None,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // Failed tests have their captured output printed in sections headed `---- <name> stdout ----`,
+    // which are followed by a list of the failures.
+    let mut sections: Vec<(&str, Vec<&str>)> = Vec::new();
+    let mut in_section = false;
+    for line in lines {
+        if let Some(name) = line
+            .strip_prefix("---- ")
+            .and_then(|x| x.strip_suffix(" stdout ----"))
+        {
+            sections.push((name, Vec::new()));
+            in_section = true;
+        } else if line == "failures:" {
+            in_section = false;
+        } else if let Some((_, output)) = sections.last_mut().filter(|_| in_section) {
+            output.push(line);
+        }
+    }
+
+    for (name, output) in sections {
+        if let Some(test) = tests.iter_mut().find(|x| x.name == name) {
+            test.output = Some(output.join("\n").trim_end().to_string());
+        }
+    }
+
+    tests
+}
+
+/// Read and remove the results the runner recorded in `path`.
+pub(crate) fn take_report(path: &Path) -> anyhow::Result<Vec<RunResult>> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {path:?}")),
+    };
+    fs::remove_file(path).with_context(|| format!("failed to remove {path:?}"))?;
+
+    data.lines()
+        .map(|line| serde_json::from_str(line).with_context(|| format!("failed to parse {path:?}")))
+        .collect()
+}
+
+/// Print whether each binary run on the device passed, followed by the totals.
+pub(crate) fn print_summary(
+    shell: &mut Shell,
+    results: &[(Target, RunResult)],
+) -> anyhow::Result<()> {
+    for (target, result) in results {
+        if result.code == 0 {
+            shell.status("Passed", format!("{target}: {}", result.name))?;
+        } else {
+            shell.status_with_color(
+                "Failed",
+                format!("{target}: {} (exit code {})", result.name, result.code),
+                termcolor::Color::Red,
+            )?;
+        }
+    }
+
+    let failed = results.iter().filter(|(_, x)| x.code != 0).count();
+    shell.status(
+        "Summary",
+        format!(
+            "{} binaries passed, {failed} failed",
+            results.len() - failed
+        ),
+    )
+}
+
+/// Quote `arg` for a POSIX shell by wrapping it in single quotes.
+pub(crate) fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("plain"), "'plain'");
+        assert_eq!(shell_quote("two words"), "'two words'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote(r#"say "hi""#), r#"'say "hi"'"#);
+        assert_eq!(shell_quote("$HOME `id`"), "'$HOME `id`'");
+        assert_eq!(shell_quote(""), "''");
+    }
+}
@@ -294,7 +294,12 @@ impl Shell {
     }
 
     /// Updates the color choice (always, never, or auto) from a string..
+    ///
+    /// Without a `--color` argument, `CARGO_TERM_COLOR` is used as cargo does, so that
+    /// cargo-ndk's output is colored the same as the diagnostics cargo renders.
     pub fn set_color_choice(&mut self, color: Option<&str>) -> anyhow::Result<()> {
+        let env_color = std::env::var("CARGO_TERM_COLOR").ok();
+        let color = color.or(env_color.as_deref());
         if let ShellOut::Stream {
             ref mut stdout,
             ref mut stderr,
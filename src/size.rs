@@ -0,0 +1,129 @@
+use std::{fs, path::Path};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{meta::Target, shell::Shell};
+
+/// The size on disk of a library copied into the output directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct LibrarySize {
+    pub abi: String,
+    pub name: String,
+    pub bytes: u64,
+}
+
+impl LibrarySize {
+    pub(crate) fn from_path(target: &Target, path: &Path) -> anyhow::Result<Self> {
+        let bytes = path
+            .metadata()
+            .with_context(|| format!("failed getting metadata for {path:?}"))?
+            .len();
+
+        Ok(Self {
+            abi: target.to_string(),
+            name: path
+                .file_name()
+                .map(|x| x.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            bytes,
+        })
+    }
+}
+
+/// The sizes of all libraries produced by a build, as written by `--size-manifest`.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SizeManifest {
+    pub libraries: Vec<LibrarySize>,
+}
+
+impl SizeManifest {
+    pub(crate) fn read(path: &Path) -> anyhow::Result<Self> {
+        let data = fs::read_to_string(path).with_context(|| format!("failed to read {path:?}"))?;
+        serde_json::from_str(&data).with_context(|| format!("failed to parse {path:?}"))
+    }
+
+    pub(crate) fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let data = serde_json::to_string_pretty(self)?;
+        fs::write(path, data).with_context(|| format!("failed to write {path:?}"))
+    }
+}
+
+/// Print the size of each library, followed by the total across all ABIs.
+pub(crate) fn print_summary(shell: &mut Shell, sizes: &[LibrarySize]) -> anyhow::Result<()> {
+    for size in sizes {
+        shell.status(
+            "Size",
+            format!("{}/{}: {}", size.abi, size.name, format_bytes(size.bytes)),
+        )?;
+    }
+
+    let total = sizes.iter().map(|x| x.bytes).sum();
+    shell.status("Total", format_bytes(total))
+}
+
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+/// Print the change in size of each library relative to `baseline`.
+///
+/// Returns `true` if any library grew by more than `threshold` percent.
+pub(crate) fn print_diff(
+    shell: &mut Shell,
+    sizes: &[LibrarySize],
+    baseline: &SizeManifest,
+    threshold: Option<f64>,
+) -> anyhow::Result<bool> {
+    let mut exceeded = false;
+
+    for size in sizes {
+        let label = format!("{}/{}", size.abi, size.name);
+        let Some(old) = baseline
+            .libraries
+            .iter()
+            .find(|x| x.abi == size.abi && x.name == size.name)
+        else {
+            shell.status("Added", format!("{label}: {}", format_bytes(size.bytes)))?;
+            continue;
+        };
+
+        let delta = size.bytes as i64 - old.bytes as i64;
+        let percent = if old.bytes == 0 {
+            0.0
+        } else {
+            delta as f64 / old.bytes as f64 * 100.0
+        };
+        let message = format!("{label}: {delta:+} B ({percent:+.1}%)");
+
+        if threshold.is_some_and(|threshold| percent > threshold) {
+            shell.error(format!("{message} exceeds the size threshold"))?;
+            exceeded = true;
+        } else {
+            shell.status("Changed", message)?;
+        }
+    }
+
+    for old in baseline
+        .libraries
+        .iter()
+        .filter(|old| !sizes.iter().any(|x| x.abi == old.abi && x.name == old.name))
+    {
+        shell.status("Removed", format!("{}/{}", old.abi, old.name))?;
+    }
+
+    Ok(exceeded)
+}
@@ -0,0 +1,160 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::Context;
+
+use crate::{meta::Target, runner::adb_path, shell::Shell};
+
+/// A program that `dlopen`s each library it's given, as `System.loadLibrary` would, reporting
+/// each as `ok` or with the linker's error.
+const DLOPEN_C: &str = r#"#include <dlfcn.h>
+#include <stdio.h>
+
+int main(int argc, char **argv) {
+    int failed = 0;
+    for (int i = 1; i < argc; i++) {
+        if (dlopen(argv[i], RTLD_NOW | RTLD_LOCAL)) {
+            printf("ok\t%s\n", argv[i]);
+        } else {
+            printf("failed\t%s\t%s\n", argv[i], dlerror());
+            failed = 1;
+        }
+    }
+    return failed;
+}
+"#;
+
+const HELPER: &str = "cargo-ndk-dlopen";
+
+/// The ABIs the connected device can load libraries for, from `ro.product.cpu.abilist`.
+pub(crate) fn device_abis() -> anyhow::Result<Vec<String>> {
+    let adb = adb_path();
+    let output = Command::new(&adb)
+        .args(["shell", "getprop", "ro.product.cpu.abilist"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("failed to run {adb:?}"))?;
+    if !output.status.success() {
+        anyhow::bail!("failed to ask the device for its ABIs; is one connected?");
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .split(',')
+        .filter(|abi| !abi.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Push `libraries` to the device, along with the `dependencies` they may need, and `dlopen`
+/// each of them there, so that unresolved symbols and missing `DT_NEEDED` libraries show up
+/// before the app loads them.
+///
+/// The helper doing the loading is built for `target` in `work_dir`. Returns whether every
+/// library loaded.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn check(
+    shell: &mut Shell,
+    ndk_home: &Path,
+    target: &Target,
+    platform: u8,
+    work_dir: &Path,
+    libraries: &[PathBuf],
+    dependencies: &[PathBuf],
+    quiet: bool,
+) -> anyhow::Result<bool> {
+    fs::create_dir_all(work_dir).with_context(|| format!("failed to create {work_dir:?}"))?;
+    let source = work_dir.join(format!("{HELPER}.c"));
+    fs::write(&source, DLOPEN_C).with_context(|| format!("failed to write {source:?}"))?;
+    let helper = work_dir.join(HELPER);
+    crate::cargo::compile_c(ndk_home, target.triple(), platform, &source, &helper)?;
+
+    let adb = adb_path();
+    let device_dir = format!("{}/smoke-test/{target}", crate::runner::device_dir());
+    let status = Command::new(&adb)
+        .arg("shell")
+        .arg(format!(
+            "rm -rf {dir} && mkdir -p {dir}",
+            dir = crate::runner::shell_quote(&device_dir)
+        ))
+        .status()
+        .with_context(|| format!("failed to run {adb:?}"))?;
+    if !status.success() {
+        anyhow::bail!("failed to create {device_dir} on the device");
+    }
+
+    for file in [&helper].into_iter().chain(libraries).chain(dependencies) {
+        let status = Command::new(&adb)
+            .arg("push")
+            .arg(file)
+            .arg(format!("{device_dir}/"))
+            .stdout(if quiet {
+                Stdio::null()
+            } else {
+                Stdio::inherit()
+            })
+            .status()
+            .with_context(|| format!("failed to run {adb:?}"))?;
+        if !status.success() {
+            anyhow::bail!("failed to push {} to the device", file.display());
+        }
+    }
+
+    let names = libraries
+        .iter()
+        .map(|library| {
+            let name = library.file_name().unwrap().to_string_lossy();
+            crate::runner::shell_quote(&format!("./{name}"))
+        })
+        .collect::<Vec<_>>();
+    let dir = crate::runner::shell_quote(&device_dir);
+    let output = Command::new(&adb)
+        .arg("shell")
+        .arg(format!(
+            "cd {dir} && chmod 755 ./{HELPER} && LD_LIBRARY_PATH={dir} ./{HELPER} {}",
+            names.join(" ")
+        ))
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("failed to run {adb:?}"))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut passed = output.status.success();
+    let mut reported = 0;
+    for line in stdout.lines() {
+        let mut fields = line.splitn(3, '\t');
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some("ok"), Some(name), _) => {
+                reported += 1;
+                shell.status(
+                    "Loaded",
+                    format!("{} ({target})", name.trim_start_matches("./")),
+                )?;
+            }
+            (Some("failed"), Some(name), error) => {
+                reported += 1;
+                passed = false;
+                shell.error(format!(
+                    "{} failed to load on the device ({target}): {}",
+                    name.trim_start_matches("./"),
+                    error.unwrap_or("unknown error")
+                ))?;
+            }
+            _ => shell.note(line)?,
+        }
+    }
+    // The helper itself couldn't run, or stopped partway
+    if reported < libraries.len() {
+        passed = false;
+        shell.error(format!(
+            "the dlopen check didn't run on the device for {target} ({})",
+            output.status
+        ))?;
+    }
+
+    Ok(passed)
+}
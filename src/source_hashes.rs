@@ -0,0 +1,77 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+
+use crate::meta::Target;
+
+/// The SHA-256 of each library as cargo built it, recorded when it was last copied into an ABI's
+/// output directory, for `--copy-if-changed`.
+///
+/// The copy in the output directory is usually stripped, so it can't be compared with the
+/// library cargo built, only with what that library was when it was copied. Like the output lock,
+/// the record is kept beside the ABI's directory so it isn't packaged with the libraries.
+pub(crate) struct SourceHashes {
+    path: PathBuf,
+    hashes: BTreeMap<String, String>,
+}
+
+impl SourceHashes {
+    /// Read the record for `target`, starting afresh if there isn't one or it can't be read.
+    pub fn load(output_dir: &Path, target: &Target) -> Self {
+        let path = output_dir.join(format!(".cargo-ndk-{target}.sha256.json"));
+        let hashes = fs::read_to_string(&path)
+            .ok()
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_default();
+        Self { path, hashes }
+    }
+
+    /// Whether `dest` is a copy of the library `name` with the content `hash`.
+    ///
+    /// Without a record of it, an unstripped copy can still be compared with the library itself.
+    pub fn is_fresh(
+        &self,
+        name: &str,
+        hash: &str,
+        dest: &Path,
+        stripped: bool,
+    ) -> anyhow::Result<bool> {
+        if !dest.exists() {
+            return Ok(false);
+        }
+        match self.hashes.get(name) {
+            Some(recorded) => Ok(recorded == hash),
+            None if !stripped => Ok(sha256(dest)? == hash),
+            None => Ok(false),
+        }
+    }
+
+    pub fn insert(&mut self, name: &str, hash: String) {
+        self.hashes.insert(name.to_string(), hash);
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        let data = serde_json::to_string_pretty(&self.hashes)?;
+        crate::cli::replace_file(&self.path, |tmp| {
+            fs::write(tmp, &data).with_context(|| format!("failed to write {tmp:?}"))
+        })
+    }
+}
+
+/// The SHA-256 of a file's content, in hex.
+pub(crate) fn sha256(path: &Path) -> anyhow::Result<String> {
+    let mut file = File::open(path).with_context(|| format!("failed to open {path:?}"))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).with_context(|| format!("failed to read {path:?}"))?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
@@ -0,0 +1,32 @@
+use std::{fs, path::Path};
+
+use anyhow::Context;
+use serde::Serialize;
+
+/// An unstripped library kept in the symbols dir.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct SymbolFile {
+    /// The GNU build ID shared by the unstripped and the stripped library, if it has one.
+    pub build_id: Option<String>,
+    pub abi: String,
+    pub name: String,
+    /// The path of the unstripped library, relative to the symbols dir.
+    pub path: String,
+}
+
+/// The libraries in the symbols dir, written to `build-ids.json` there so that an upload step can
+/// find the file for each build ID.
+#[derive(Debug, Serialize)]
+pub(crate) struct SymbolIndex {
+    pub libraries: Vec<SymbolFile>,
+}
+
+impl SymbolIndex {
+    pub(crate) const FILE_NAME: &'static str = "build-ids.json";
+
+    pub(crate) fn write(&self, symbols_dir: &Path) -> anyhow::Result<()> {
+        let path = symbols_dir.join(Self::FILE_NAME);
+        let data = serde_json::to_string_pretty(self)?;
+        fs::write(&path, data).with_context(|| format!("failed to write {path:?}"))
+    }
+}
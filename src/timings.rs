@@ -0,0 +1,35 @@
+use std::{fs, path::Path, time::Duration};
+
+use anyhow::Context;
+use serde::Serialize;
+
+/// How long one target took to build, and what it produced.
+#[derive(Debug, Serialize)]
+pub(crate) struct TargetTiming {
+    pub abi: String,
+    pub triple: String,
+    pub secs: f64,
+    pub artifacts: usize,
+    pub bytes: u64,
+}
+
+/// The build times of all targets, as written by `--timings-json`.
+#[derive(Debug, Serialize)]
+pub(crate) struct TimingsReport {
+    pub total_secs: f64,
+    pub targets: Vec<TargetTiming>,
+}
+
+impl TimingsReport {
+    pub(crate) fn new(total: Duration, targets: Vec<TargetTiming>) -> Self {
+        Self {
+            total_secs: total.as_secs_f64(),
+            targets,
+        }
+    }
+
+    pub(crate) fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let data = serde_json::to_string_pretty(self)?;
+        fs::write(path, data).with_context(|| format!("failed to write {path:?}"))
+    }
+}
@@ -0,0 +1,95 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::mpsc::{self, RecvTimeoutError},
+    time::Duration,
+};
+
+use anyhow::Context;
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use crate::shell::Shell;
+
+/// How long to wait for changes to settle before rebuilding, so that saving several files, or
+/// a checkout, only builds once.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Build with the given cargo-ndk arguments, then again whenever a file in the workspace
+/// changes, for `cargo ndk watch`.
+///
+/// Each build is a separate run of cargo-ndk, so a failed one only waits for the next change.
+/// The files the build itself writes, under the target directory and the output directory,
+/// are ignored, as is anything that changes while building.
+pub(crate) fn run(
+    shell: &mut Shell,
+    args: &[String],
+    workspace_root: &Path,
+    ignored: &[PathBuf],
+) -> anyhow::Result<()> {
+    let exe = env::current_exe().context("failed to find the cargo-ndk executable")?;
+    // Event paths are absolute, and the output directory may not have been created yet
+    let cwd = env::current_dir().context("failed to find the current directory")?;
+    let cwd = dunce::canonicalize(&cwd).unwrap_or(cwd);
+    let ignored = ignored
+        .iter()
+        .map(|path| {
+            let path = cwd.join(path);
+            dunce::canonicalize(&path).unwrap_or(path)
+        })
+        .chain([workspace_root.join(".git")])
+        .collect::<Vec<_>>();
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).context("failed to watch for changes")?;
+    watcher
+        .watch(workspace_root, RecursiveMode::Recursive)
+        .with_context(|| format!("failed to watch {}", workspace_root.display()))?;
+
+    let is_relevant = |event: &notify::Event| {
+        !matches!(event.kind, EventKind::Access(_) | EventKind::Other)
+            && event
+                .paths
+                .iter()
+                .any(|path| !ignored.iter().any(|dir| path.starts_with(dir)))
+    };
+
+    loop {
+        let status = Command::new(&exe)
+            .arg("ndk")
+            .args(args)
+            .status()
+            .with_context(|| format!("failed to run {exe:?}"))?;
+        if let Some(signal) = crate::process::killed_by(&status) {
+            std::process::exit(128 + signal);
+        }
+
+        // Changes made while building, such as cargo updating `Cargo.lock` or build scripts
+        // writing into the source tree, would otherwise start another build straight away
+        rx.try_iter().for_each(drop);
+
+        shell.status(
+            "Watching",
+            format!("{} for changes", workspace_root.display()),
+        )?;
+
+        // Wait for a change, then for them to stop
+        loop {
+            let event = rx.recv().context("stopped watching for changes")?;
+            if event.as_ref().is_ok_and(is_relevant) {
+                break;
+            }
+        }
+        loop {
+            match rx.recv_timeout(DEBOUNCE) {
+                Ok(_) => continue,
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    anyhow::bail!("stopped watching for changes")
+                }
+            }
+        }
+
+        shell.status("Rebuilding", "after changes")?;
+    }
+}
@@ -0,0 +1,78 @@
+//! cargo-ndk-runner is run by cargo for each binary it selects, which is relied upon for cargo's
+//! target selection flags, like `--test`, to pick what's run on the device.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+fn write(path: &Path, contents: &str) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+/// Run `cargo test` with `args` for a crate with the integration tests `one` and `two`, with
+/// cargo-ndk-runner as the runner.
+fn dry_run(name: &str, args: &[&str]) -> Output {
+    let dir = env::temp_dir().join(format!("cargo-ndk-runner-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    write(
+        &dir.join("Cargo.toml"),
+        "[package]\nname = \"selection\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[workspace]\n",
+    );
+    write(&dir.join("src").join("lib.rs"), "");
+    for name in ["one", "two"] {
+        write(
+            &dir.join("tests").join(format!("{name}.rs")),
+            "#[test]\nfn it_works() {}\n",
+        );
+    }
+
+    // The runner is set for the host, as the test can't build for Android, and only says what it
+    // would push and run, so that no device is needed
+    let runner = serde_json::to_string(env!("CARGO_BIN_EXE_cargo-ndk-runner")).unwrap();
+    let cargo = env::var_os("CARGO").map_or_else(|| PathBuf::from("cargo"), PathBuf::from);
+    let output = Command::new(cargo)
+        .current_dir(&dir)
+        .arg("test")
+        .arg("--config")
+        .arg(format!("target.'cfg(all())'.runner = {runner}"))
+        .args(args)
+        .env("_CARGO_NDK_RUNNER_DRY_RUN", "1")
+        .env_remove("CARGO_TARGET_DIR")
+        .output()
+        .unwrap();
+    let _ = fs::remove_dir_all(&dir);
+    output
+}
+
+#[test]
+fn test_selection_runs_exactly_one_binary() {
+    let output = dry_run("selection", &["--test", "one"]);
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    let pushed = stderr
+        .lines()
+        .filter(|line| line.trim_start().starts_with("Would push"))
+        .collect::<Vec<_>>();
+    assert_eq!(pushed.len(), 1, "{stderr}");
+    assert!(pushed[0].contains("one-"), "{stderr}");
+}
+
+#[test]
+fn test_args_reach_the_device_intact() {
+    let output = dry_run("args", &["--test", "one", "--", "it's $HOME", "--exact"]);
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    let run = stderr
+        .lines()
+        .find(|line| line.trim_start().starts_with("Would run"))
+        .unwrap_or_else(|| panic!("{stderr}"));
+    assert!(
+        run.ends_with(r"'it'\''s $HOME' '--exact'"),
+        "{stderr}"
+    );
+}